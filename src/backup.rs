@@ -0,0 +1,247 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use rusqlite::Connection;
+use rusqlite::backup::{Backup, StepResult};
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::options::parse_option;
+
+const DEFAULT_BACKUP_HOURS: u64 = 24;
+const DEFAULT_BACKUP_KEEP: usize = 7;
+// times to retry a busy or locked source before giving up
+const BACKUP_RETRIES: usize = 50;
+const BACKUP_RETRY_PAUSE: Duration = Duration::from_millis(100);
+// strftime format of the timestamp in rotated backup names
+const STAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+const STAMP_LEN: usize = 15;
+
+// Scheduled backups taken by the irc bot
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    // rotated backups to keep, oldest are removed first
+    pub keep: usize,
+}
+
+impl BackupConfig {
+    // None unless backup_dir is set
+    pub fn from_irc_config(config: &Config) -> Result<Option<BackupConfig>> {
+        let dir = match config.options.get("backup_dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => return Ok(None)
+        };
+        let hours: u64 = parse_option(config, "backup_hours", "hours", DEFAULT_BACKUP_HOURS)?;
+        if hours == 0 {
+            return Err(Error::Config("backup_hours should be at least 1".to_string()));
+        }
+        let keep: usize = parse_option(config, "backup_keep", "a count", DEFAULT_BACKUP_KEEP)?;
+        if keep == 0 {
+            return Err(Error::Config("backup_keep should be at least 1".to_string()));
+        }
+        Ok(Some(BackupConfig {
+            dir,
+            interval: Duration::from_secs(hours * 60 * 60),
+            keep
+        }))
+    }
+}
+
+/// Copy db to dest with sqlite's online backup api
+///
+/// The copy is a consistent snapshot even while another connection is
+/// writing.  It's written next to dest and renamed into place, so an
+/// interrupted backup never leaves a partial file at dest.
+pub fn backup(db: &Connection, dest: &Path) -> Result<()> {
+    if let Some(source) = db_path(db)? {
+        if dest.exists() && fs::canonicalize(dest)? == fs::canonicalize(source)? {
+            return Err(Error::Config(
+                format!("Refusing to back up the database over itself: {}", dest.display())));
+        }
+    }
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    if partial.exists() {
+        fs::remove_file(&partial)?;
+    }
+    if let Err(e) = copy_pages(db, &partial) {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    fs::rename(&partial, dest)?;
+    Ok(())
+}
+
+fn copy_pages(db: &Connection, dest: &Path) -> Result<()> {
+    let mut copy = Connection::open(dest)?;
+    let backup = Backup::new(db, &mut copy)?;
+    let mut retries = 0;
+    loop {
+        // all pages in one step, so writes from other connections
+        // can't restart the copy part way through
+        match backup.step(-1)? {
+            StepResult::Done => return Ok(()),
+            StepResult::More => (),
+            StepResult::Busy | StepResult::Locked if retries < BACKUP_RETRIES => {
+                retries += 1;
+                thread::sleep(BACKUP_RETRY_PAUSE);
+            }
+            StepResult::Busy | StepResult::Locked => return Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut, "database stayed busy during backup")))
+        }
+    }
+}
+
+// file backing the main database, None for in-memory databases
+fn db_path(db: &Connection) -> Result<Option<PathBuf>> {
+    let mut stmt = db.prepare("PRAGMA database_list")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let name: String = row.get(1);
+        let file: String = row.get(2);
+        if name == "main" && !file.is_empty() {
+            return Ok(Some(PathBuf::from(file)));
+        }
+    }
+    Ok(None)
+}
+
+/// Back up db into config.dir as <stem>-<timestamp>.db, then remove
+/// the oldest backups past config.keep.  Returns the new backup's path.
+pub fn rotate(db: &Connection, db_url: &str, config: &BackupConfig) -> Result<PathBuf> {
+    let stem = backup_stem(db_url);
+    fs::create_dir_all(&config.dir)?;
+    let stamp = chrono::Local::now().format(STAMP_FORMAT);
+    let dest = config.dir.join(format!("{}-{}.db", stem, stamp));
+    backup(db, &dest)?;
+    for old in prune(&config.dir, &stem, config.keep)? {
+        info!("Removed old backup {}", old.display());
+    }
+    Ok(dest)
+}
+
+fn backup_stem(db_url: &str) -> String {
+    Path::new(db_url).file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .filter(|s| !s.is_empty() && s != ":memory:")
+        .unwrap_or_else(|| "bazbot".to_string())
+}
+
+// remove all but the newest keep backups named <stem>-<timestamp>.db
+fn prune(dir: &Path, stem: &str, keep: usize) -> Result<Vec<PathBuf>> {
+    let prefix = format!("{}-", stem);
+    let mut backups = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let is_backup = name.starts_with(&prefix) && name.ends_with(".db")
+            && name.len() == prefix.len() + STAMP_LEN + 3
+            && name[prefix.len()..prefix.len() + STAMP_LEN].chars()
+                .all(|c| c.is_ascii_digit() || c == '-');
+        if is_backup {
+            backups.push(name);
+        }
+    }
+    // timestamps sort oldest first
+    backups.sort();
+    let remove = backups.len().saturating_sub(keep);
+    let mut removed = vec![];
+    for name in backups.into_iter().take(remove) {
+        let path = dir.join(name);
+        fs::remove_file(&path)?;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+    use std::env;
+
+    // a directory that's removed when dropped
+    struct TempDir(PathBuf);
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("bazbot-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn backup_config() {
+        assert_eq!(None, BackupConfig::from_irc_config(&irc_config(&[])).unwrap());
+        let config = BackupConfig::from_irc_config(&irc_config(&[
+            ("backup_dir", "backups"), ("backup_hours", "6"), ("backup_keep", "3")])).unwrap();
+        assert_eq!(Some(BackupConfig {
+            dir: PathBuf::from("backups"),
+            interval: Duration::from_secs(6 * 60 * 60),
+            keep: 3
+        }), config);
+        for bad in &[("backup_hours", "0"), ("backup_keep", "0"), ("backup_keep", "many")] {
+            match BackupConfig::from_irc_config(&irc_config(&[("backup_dir", "b"), *bad])) {
+                Err(Error::Config(_)) => (),
+                other => panic!("expected config error for {:?}, got {:?}", bad, other)
+            }
+        }
+    }
+
+    #[test]
+    fn backup_while_writing() {
+        let dir = TempDir::new("backup");
+        let source = dir.0.join("words.db");
+        let writer = Connection::open(&source).unwrap();
+        writer.execute_batch("
+            PRAGMA journal_mode=WAL;
+            create table t (x integer);
+            insert into t values (1), (2);
+            begin;
+            insert into t values (3);").unwrap();
+        let reader = Connection::open(&source).unwrap();
+        let dest = dir.0.join("copy.db");
+        backup(&reader, &dest).expect("backup");
+        let copy = Connection::open(&dest).unwrap();
+        let count: i64 = copy.query_row("select count(*) from t", &[], |row| row.get(0)).unwrap();
+        // uncommitted rows aren't in the snapshot
+        assert_eq!(2, count);
+        assert!(!dir.0.join("copy.db.partial").exists());
+        match backup(&reader, &source) {
+            Err(Error::Config(_)) => (),
+            other => panic!("expected refusal to overwrite source, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rotate_keeps_newest() {
+        let dir = TempDir::new("rotate");
+        for name in &["words-20200101-000000.db", "words-20200102-000000.db",
+                      "words-20200103-000000.db", "words.db", "other-20200101-000000.db",
+                      "words-notes.db"] {
+            fs::write(dir.0.join(name), "").unwrap();
+        }
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch("create table t (x integer);").unwrap();
+        let config = BackupConfig { dir: dir.0.clone(), interval: Duration::from_secs(1), keep: 2 };
+        let newest = rotate(&db, "data/words.db", &config).expect("rotate");
+        let mut left: Vec<String> = fs::read_dir(&dir.0).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        let newest = newest.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(vec!["other-20200101-000000.db", "words-20200103-000000.db",
+                        &newest, "words-notes.db", "words.db"], left);
+    }
+}
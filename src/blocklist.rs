@@ -0,0 +1,236 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use regex::{Regex, RegexBuilder};
+
+use crate::commands::{Action, Call, Command, Level};
+use crate::error::{Error, Result};
+use crate::ignore::Removed;
+use crate::markov_words::{fold_spelling, WordsDb};
+
+#[derive(Debug)]
+enum Matcher {
+    // folded spelling, so "Word," is blocked by "word"
+    Word(String),
+    // case insensitive search in the spelling
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Entry {
+    text: String,
+    matcher: Matcher,
+    // entries from the config can't be removed at runtime
+    from_config: bool,
+}
+
+impl Entry {
+    // /regex/ is searched for in a word, anything else is a word
+    fn parse(text: &str, from_config: bool) -> Result<Entry> {
+        let text = text.trim();
+        let matcher = if text.len() > 1 && text.starts_with('/') && text.ends_with('/') {
+            let pattern = &text[1..text.len() - 1];
+            Matcher::Regex(RegexBuilder::new(pattern).case_insensitive(true).build().map_err(|e| {
+                Error::Config(format!("Bad block regex {}: {}", text, e))
+            })?)
+        } else if !text.is_empty() && !text.contains(char::is_whitespace) {
+            Matcher::Word(fold_spelling(text))
+        } else {
+            return Err(Error::Config(format!("Block entries are one word or /regex/, not {:?}", text)));
+        };
+        Ok(Entry { text: text.to_string(), matcher, from_config })
+    }
+
+    fn blocks(&self, spelling: &str) -> bool {
+        match &self.matcher {
+            Matcher::Word(folded) => fold_spelling(spelling) == *folded,
+            Matcher::Regex(regex) => regex.is_match(spelling)
+        }
+    }
+}
+
+// Words the bot never says, checked as each word of a reply is chosen
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    entries: Vec<Entry>,
+}
+
+impl Blocklist {
+    // comma separated entries from the block option
+    pub fn from_irc_config(config: &Config) -> Result<Blocklist> {
+        let mut blocklist = Blocklist::default();
+        if let Some(entries) = config.options.get("block") {
+            for entry in entries.split(',').filter(|e| !e.trim().is_empty()) {
+                blocklist.insert(Entry::parse(entry, true)?);
+            }
+        }
+        Ok(blocklist)
+    }
+
+    // entries stored in the database, skipping any that no longer parse
+    pub fn load(&mut self, stored: Vec<String>) {
+        for entry in stored {
+            match Entry::parse(&entry, false) {
+                Ok(entry) => self.insert(entry),
+                Err(e) => warn!("Skipping stored block entry: {}", e)
+            }
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        if !self.entries.iter().any(|e| e.text == entry.text) {
+            self.entries.push(entry);
+        }
+    }
+
+    /// Parse and add an entry, returning its text as it should be stored
+    pub fn add(&mut self, text: &str) -> Result<String> {
+        let entry = Entry::parse(text, false)?;
+        let text = entry.text.clone();
+        self.insert(entry);
+        Ok(text)
+    }
+
+    pub fn remove(&mut self, text: &str) -> Removed {
+        match self.entries.iter().position(|e| e.text == text) {
+            Some(pos) if self.entries[pos].from_config => Removed::FromConfig,
+            Some(pos) => {
+                self.entries.remove(pos);
+                Removed::Removed
+            }
+            None => Removed::NotFound
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn blocks(&self, spelling: &str) -> bool {
+        self.entries.iter().any(|e| e.blocks(spelling))
+    }
+
+    pub fn entries(&self) -> Vec<String> {
+        self.entries.iter().map(|e| e.text.clone()).collect()
+    }
+}
+
+// !baz block WORD|/REGEX/, stored in the database
+pub struct Block;
+
+impl Command for Block {
+    fn name(&self) -> &'static str {
+        "block"
+    }
+    fn usage(&self) -> &'static str {
+        "[WORD|/REGEX/]"
+    }
+    fn help(&self) -> &'static str {
+        "never say a word, or list the blocked words"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let reply = match call.args.first() {
+            Some(text) => match words.block(text, &call.caller.mask) {
+                Ok(entry) => {
+                    info!("{} blocked {}", call.caller.mask, entry);
+                    format!("blocked {}", entry)
+                }
+                Err(Error::Config(msg)) => msg,
+                Err(e) => return Err(e)
+            },
+            None => {
+                let entries = words.blocklist();
+                if entries.is_empty() {
+                    "nothing is blocked".to_string()
+                } else {
+                    format!("blocked: {}", entries.join(", "))
+                }
+            }
+        };
+        Ok(vec![Action::Say(reply)])
+    }
+}
+
+pub struct Unblock;
+
+impl Command for Unblock {
+    fn name(&self) -> &'static str {
+        "unblock"
+    }
+    fn usage(&self) -> &'static str {
+        "ENTRY"
+    }
+    fn help(&self) -> &'static str {
+        "remove a blocked word added with block"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn min_args(&self) -> usize {
+        1
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let text = call.args[0].trim();
+        let reply = match words.unblock(text)? {
+            Removed::Removed => {
+                info!("{} unblocked {}", call.caller.mask, text);
+                format!("unblocked {}", text)
+            }
+            Removed::FromConfig => format!("{} is in the config file, remove it there", text),
+            Removed::NotFound => format!("{} isn't blocked", text)
+        };
+        Ok(vec![Action::Say(reply)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{split_args, Caller, Commands};
+    use crate::options::irc_config;
+
+    #[test]
+    fn match_entries() {
+        let blocklist = Blocklist::from_irc_config(&irc_config(&[("block", "Darn, /^heck/")])).expect("parse");
+        assert!(blocklist.blocks("darn!"));
+        assert!(blocklist.blocks("Heckin"));
+        assert!(!blocklist.blocks("darning"));
+        assert!(!blocklist.blocks("oheck"));
+        assert!(Entry::parse("/(/", false).is_err());
+        assert!(Entry::parse("two words", false).is_err());
+    }
+
+    #[test]
+    fn runtime_entries() {
+        let mut w = WordsDb::new(":memory:".to_string()).expect("open");
+        w.migrate().expect("migrate");
+        let mut commands = Commands::new("!baz", vec![]);
+        commands.register(Box::new(Block));
+        commands.register(Box::new(Unblock));
+        let admin = Caller { nick: "root".to_string(), mask: "root!r@host".to_string(), level: Level::Admin };
+        let run = |w: &mut WordsDb, text: &str| match commands.run(w, &admin, "#a", &split_args(text)).expect("run").as_slice() {
+            [Action::Say(reply)] => reply.clone(),
+            other => panic!("expected a reply, got {:?}", other)
+        };
+
+        assert_eq!("nothing is blocked", run(&mut w, "block"));
+        assert_eq!("blocked darn", run(&mut w, "block darn"));
+        assert_eq!("blocked /h.ck/", run(&mut w, "block /h.ck/"));
+        assert_eq!("blocked: darn, /h.ck/", run(&mut w, "block"));
+        assert_eq!("unblocked darn", run(&mut w, "unblock darn"));
+        assert_eq!("darn isn't blocked", run(&mut w, "unblock darn"));
+
+        // stored entries come back on restart
+        let mut reloaded = Blocklist::default();
+        reloaded.load(w.stored_blocklist().expect("stored"));
+        assert_eq!(vec!["/h.ck/"], reloaded.entries());
+    }
+}
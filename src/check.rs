@@ -0,0 +1,394 @@
+use rusqlite::Connection;
+
+use crate::error::Result;
+use crate::markov_words::WordsDb;
+use crate::migration::{self, MigrationState};
+
+// tables of (word1, word2, word3, freq) chains
+const PHRASE_TABLES: [&str; 2] = ["phrases", "action_phrases"];
+
+#[derive(Debug, PartialEq)]
+pub enum Repair {
+    // found, but --repair wasn't given
+    NotAttempted,
+    Repaired,
+    // needs a person to look at it
+    Unrepairable,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub check: &'static str,
+    pub detail: String,
+    pub repair: Repair,
+}
+
+// Collects problems, and runs repairs for the ones that can be fixed
+struct Checker<'a> {
+    db: &'a Connection,
+    repair: bool,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn unrepairable(&mut self, check: &'static str, detail: String) {
+        self.problems.push(Problem { check, detail, repair: Repair::Unrepairable });
+    }
+
+    // record a problem, and fix it when repairing
+    fn repairable<F: FnOnce(&Connection) -> Result<()>>(&mut self, check: &'static str, detail: String, fix: F) -> Result<()> {
+        let repair = if self.repair {
+            fix(self.db)?;
+            Repair::Repaired
+        } else {
+            Repair::NotAttempted
+        };
+        self.problems.push(Problem { check, detail, repair });
+        Ok(())
+    }
+
+    fn count(&self, sql: &str) -> Result<i64> {
+        Ok(self.db.query_row(sql, &[], |row| row.get(0))?)
+    }
+
+    fn strings(&self, sql: &str) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare(sql)?;
+        let rows = stmt.query_map(&[], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(rows)
+    }
+
+    fn has_index(&self, name: &str) -> Result<bool> {
+        let count: i64 = self.db.query_row(
+            "select count(*) from sqlite_master where type='index' and name=?",
+            &[&name], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
+    // sqlite's own page and index consistency
+    fn integrity(&mut self) -> Result<()> {
+        for line in self.strings("PRAGMA integrity_check")? {
+            if line != "ok" {
+                self.unrepairable("integrity", line);
+            }
+        }
+        Ok(())
+    }
+
+    // false if the schema isn't there to check the rest
+    fn migrations(&mut self) -> Result<bool> {
+        let unknown = migration::unknown(self.db)?;
+        if !unknown.is_empty() {
+            self.unrepairable("migrations",
+                format!("unknown migrations from a newer version: {}", unknown.join(", ")));
+            return Ok(false);
+        }
+        let statuses = migration::status(self.db)?;
+        for status in &statuses {
+            match status.state {
+                MigrationState::Changed => self.unrepairable("migrations",
+                    format!("{} has changed since it was applied", status.migration.m_id)),
+                MigrationState::Pending => self.unrepairable("migrations",
+                    format!("{} is pending, run `bazbot migrate up`", status.migration.m_id)),
+                MigrationState::Applied | MigrationState::Unverified => ()
+            }
+        }
+        if statuses.iter().any(|s| s.state == MigrationState::Pending) {
+            return Ok(false);
+        }
+        let version = migration::schema_version(self.db)?;
+        let applied = self.count("select count(*) from migrations where m_id<>'init'")?;
+        if version != applied {
+            self.repairable("migrations",
+                format!("schema version is {}, but {} migrations are applied", version, applied),
+                |db| Ok(db.execute_batch(&format!("PRAGMA user_version = {};", applied))?))?;
+        }
+        Ok(true)
+    }
+
+    // word 0 is the begin/end of phrase marker.  false if it's missing
+    // and can't be restored
+    fn sentinel(&mut self) -> Result<bool> {
+        let spelling: Option<String> = match self.db.query_row(
+            "select spelling from words where word_id=0", &[], |row| row.get(0)) {
+            Ok(spelling) => Some(spelling),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into())
+        };
+        let empty_elsewhere = self.count("select count(*) from words where spelling='' and word_id<>0")? > 0;
+        let present = spelling.is_some();
+        match spelling {
+            Some(ref s) if s.is_empty() => (),
+            _ if empty_elsewhere => self.unrepairable("sentinel",
+                "word 0 isn't the empty word, and another word is spelled ''".to_string()),
+            Some(s) => self.repairable("sentinel",
+                format!("word 0 is spelled {:?} instead of ''", s),
+                |db| Ok(db.execute_batch("update words set spelling='' where word_id=0;")?))?,
+            None => self.repairable("sentinel", "word 0 is missing".to_string(),
+                |db| Ok(db.execute_batch("insert into words (word_id, spelling) values (0,'');")?))?
+        }
+        Ok(present || !empty_elsewhere)
+    }
+
+    fn frequencies(&mut self) -> Result<()> {
+        for table in &PHRASE_TABLES {
+            let bad = self.count(&format!("select count(*) from {} where freq<=0", table))?;
+            if bad > 0 {
+                let delete = format!("delete from {} where freq<=0;", table);
+                self.repairable("frequencies",
+                    format!("{} rows in {} have zero or negative frequency, deleting them", bad, table),
+                    |db| Ok(db.execute_batch(&delete)?))?;
+            }
+        }
+        Ok(())
+    }
+
+    // phrases and word forms pointing at deleted words
+    fn foreign_keys(&mut self, sentinel: bool) -> Result<()> {
+        let mut stmt = self.db.prepare(
+            "select \"table\", count(*) from pragma_foreign_key_check group by \"table\"")?;
+        let dangling = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?
+            .collect::<rusqlite::Result<Vec<(String, i64)>>>()?;
+        for (table, count) in dangling {
+            let detail = format!("{} rows in {} refer to missing words", count, table);
+            match table.as_str() {
+                "phrases" | "action_phrases" => self.dangling_phrases(&table, sentinel)?,
+                "word_forms" => self.repairable("foreign keys", detail, |db| Ok(db.execute_batch(
+                    "delete from word_forms where rowid in
+                     (select rowid from pragma_foreign_key_check('word_forms'));")?))?,
+                _ => self.unrepairable("foreign keys", detail)
+            }
+        }
+        Ok(())
+    }
+
+    // every phrase starts and ends with word 0, so phrases that only
+    // refer to a missing word 0 are left to the sentinel repair, or
+    // kept when it can't be restored
+    fn dangling_phrases(&mut self, table: &str, sentinel: bool) -> Result<()> {
+        let missing = ["word1", "word2", "word3"].iter()
+            .map(|word| format!("({0}<>0 and {0} not in (select word_id from words))", word))
+            .collect::<Vec<_>>()
+            .join(" or ");
+        let dangling = self.count(&format!("select count(*) from {} where {}", table, missing))?;
+        if dangling > 0 {
+            let delete = format!("delete from {} where {};", table, missing);
+            self.repairable("foreign keys",
+                format!("{} rows in {} refer to missing words", dangling, table),
+                |db| Ok(db.execute_batch(&delete)?))?;
+        }
+        if !sentinel {
+            let on_sentinel = self.count(&format!(
+                "select count(*) from {} where (word1=0 or word2=0 or word3=0) and not ({})", table, missing))?;
+            if on_sentinel > 0 {
+                self.unrepairable("foreign keys",
+                    format!("{} rows in {} refer to the missing word 0", on_sentinel, table));
+            }
+        }
+        Ok(())
+    }
+
+    fn uniqueness(&mut self) -> Result<()> {
+        let spellings = self.strings(
+            "select spelling from words group by spelling having count(*) > 1")?;
+        if !spellings.is_empty() {
+            self.unrepairable("uniqueness",
+                format!("words spelled more than once: {:?}", spellings));
+        }
+        for table in &PHRASE_TABLES {
+            let phrases = self.count(&format!(
+                "select count(*) from (select 1 from {}
+                 group by word1, word2, word3 having count(*) > 1)", table))?;
+            if phrases > 0 {
+                let merge = format!("
+                    create temp table merged as
+                        select min(rowid) as keep, word1, word2, word3, sum(freq) as freq
+                        from {0} group by word1, word2, word3 having count(*) > 1;
+                    delete from {0} where rowid not in (select keep from merged)
+                        and exists (select 1 from merged m where m.word1={0}.word1
+                                    and m.word2={0}.word2 and m.word3={0}.word3);
+                    update {0} set freq=(select freq from merged where keep={0}.rowid)
+                        where rowid in (select keep from merged);
+                    drop table temp.merged;", table);
+                self.repairable("uniqueness",
+                    format!("{} rows in {} are stored more than once, merging their frequencies", phrases, table),
+                    |db| Ok(db.execute_batch(&merge)?))?;
+            }
+        }
+        for (index, sql) in &[
+            ("idx_spelling", "CREATE UNIQUE INDEX idx_spelling on words (spelling);"),
+            ("idx_phrases_u", "CREATE UNIQUE INDEX idx_phrases_u on phrases (word1,word2,word3);"),
+            ("idx_action_phrases_u",
+             "CREATE UNIQUE INDEX idx_action_phrases_u on action_phrases (word1,word2,word3);")] {
+            if self.has_index(index)? {
+                continue;
+            }
+            let detail = format!("unique index {} is missing", index);
+            if *index == "idx_spelling" && !spellings.is_empty() {
+                self.unrepairable("uniqueness", detail);
+            } else {
+                self.repairable("uniqueness", detail, |db| Ok(db.execute_batch(sql)?))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn word_forms(&mut self) -> Result<()> {
+        let missing = self.count(
+            "select count(*) from words
+             where word_id<>0 and word_id not in (select word_id from word_forms)")?;
+        if missing > 0 {
+            self.repairable("word forms",
+                format!("{} words aren't indexed for fuzzy matching", missing),
+                |db| {
+                    let mut stmt = db.prepare(
+                        "select word_id, spelling from words
+                         where word_id<>0 and word_id not in (select word_id from word_forms)")?;
+                    let words = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?
+                        .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+                    for (word_id, spelling) in words {
+                        WordsDb::add_word_forms_db(db, word_id, &spelling)?;
+                    }
+                    Ok(())
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the words database for problems, repairing what's safe to
+///
+/// Checks sqlite integrity, migrations, the word 0 sentinel, phrase and
+/// action frequencies, foreign keys, uniqueness and the fuzzy matching index.
+/// Repairs run in a single transaction.
+pub fn check(db: &Connection, repair: bool) -> Result<Vec<Problem>> {
+    let mut checker = Checker { db, repair, problems: vec![] };
+    checker.integrity()?;
+    if repair {
+        migration::in_transaction(db, || run_checks(&mut checker))?;
+    } else {
+        run_checks(&mut checker)?;
+    }
+    Ok(checker.problems)
+}
+
+fn run_checks(checker: &mut Checker) -> Result<()> {
+    if !checker.migrations()? {
+        // the tables the other checks need may not be there
+        return Ok(());
+    }
+    let sentinel = checker.sentinel()?;
+    checker.frequencies()?;
+    checker.foreign_keys(sentinel)?;
+    checker.uniqueness()?;
+    checker.word_forms()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrated() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        migration::migrate(&db).expect("migrate");
+        db.execute_batch("
+            insert into words (word_id, spelling) values (1, 'a'), (2, 'b');
+            insert into word_forms (word_id, folded, stem, len) values (1, 'a', 'a', 1), (2, 'b', 'b', 1);
+            insert into phrases (word1, word2, word3, freq) values (0, 1, 2, 3), (1, 2, 0, 3);
+        ").unwrap();
+        db
+    }
+
+    fn checks(problems: &[Problem]) -> Vec<&'static str> {
+        problems.iter().map(|p| p.check).collect()
+    }
+
+    #[test]
+    fn clean_database() {
+        let db = migrated();
+        let problems = check(&db, false).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn find_and_repair() {
+        let db = migrated();
+        db.execute_batch("
+            delete from words where word_id=0;
+            insert into phrases (word1, word2, word3, freq) values (2, 0, 9, 1);
+            update phrases set freq=0 where word1=1;
+            drop index idx_phrases_u;
+            insert into phrases (word1, word2, word3, freq) values (0, 1, 2, 2);
+            insert into words (word_id, spelling) values (3, 'c');
+        ").unwrap();
+        let problems = check(&db, false).unwrap();
+        assert_eq!(vec!["sentinel", "frequencies", "foreign keys", "uniqueness", "uniqueness", "word forms"],
+                   checks(&problems));
+        assert!(problems.iter().all(|p| p.repair == Repair::NotAttempted));
+
+        let repaired = check(&db, true).unwrap();
+        assert!(repaired.iter().all(|p| p.repair == Repair::Repaired), "{:?}", repaired);
+        let problems = check(&db, false).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+        let freq: i64 = db.query_row(
+            "select freq from phrases where word1=0 and word2=1 and word3=2", &[], |row| row.get(0)).unwrap();
+        assert_eq!(5, freq);
+    }
+
+    #[test]
+    fn repair_actions() {
+        let db = migrated();
+        db.execute_batch("
+            insert into action_phrases (word1, word2, word3, freq) values (0, 1, 2, 1), (1, 2, 0, 0), (2, 9, 0, 1);
+            drop index idx_action_phrases_u;
+            insert into action_phrases (word1, word2, word3, freq) values (0, 1, 2, 2);
+        ").unwrap();
+        let problems = check(&db, false).unwrap();
+        assert_eq!(vec!["frequencies", "foreign keys", "uniqueness", "uniqueness"], checks(&problems));
+        assert!(problems.iter().all(|p| p.detail.contains("action_phrases")), "{:?}", problems);
+
+        let repaired = check(&db, true).unwrap();
+        assert!(repaired.iter().all(|p| p.repair == Repair::Repaired), "{:?}", repaired);
+        let problems = check(&db, false).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+        let freq: i64 = db.query_row(
+            "select sum(freq) from action_phrases", &[], |row| row.get(0)).unwrap();
+        assert_eq!(3, freq);
+    }
+
+    #[test]
+    fn keep_phrases_without_sentinel() {
+        let db = migrated();
+        db.execute_batch("
+            delete from words where word_id=0;
+            insert into words (word_id, spelling) values (3, '');
+            insert into word_forms (word_id, folded, stem, len) values (3, '', '', 0);
+        ").unwrap();
+        let problems = check(&db, true).unwrap();
+        assert_eq!(vec!["sentinel", "foreign keys"], checks(&problems));
+        assert!(problems.iter().all(|p| p.repair == Repair::Unrepairable), "{:?}", problems);
+        let phrases: i64 = db.query_row("select count(*) from phrases", &[], |row| row.get(0)).unwrap();
+        assert_eq!(2, phrases);
+    }
+
+    #[test]
+    fn unrepairable_duplicate_spelling() {
+        let db = migrated();
+        db.execute_batch("
+            drop index idx_spelling;
+            insert into words (word_id, spelling) values (3, 'a');
+            insert into word_forms (word_id, folded, stem, len) values (3, 'a', 'a', 1);
+        ").unwrap();
+        let problems = check(&db, true).unwrap();
+        assert_eq!(vec!["uniqueness", "uniqueness"], checks(&problems));
+        assert!(problems.iter().all(|p| p.repair == Repair::Unrepairable));
+    }
+
+    #[test]
+    fn pending_migrations() {
+        let db = Connection::open_in_memory().unwrap();
+        let problems = check(&db, false).unwrap();
+        assert!(!problems.is_empty());
+        assert!(problems.iter().all(|p| p.check == "migrations"));
+    }
+}
@@ -0,0 +1,594 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+
+use crate::error::Result;
+use crate::hostmask;
+use crate::markov_words::{self, WordsDb};
+use crate::stats::{Counter, COUNTERS};
+
+const DEFAULT_PREFIX: &str = "!baz";
+
+// Who may run a command, lowest first
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Anyone,
+    Admin,
+}
+
+// Who sent a command, and what they're allowed to do
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub nick: String,
+    // nick!user@host
+    pub mask: String,
+    pub level: Level,
+}
+
+// What the connection should do once a command has run
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    // reply where the command was sent
+    Say(String),
+    // message a channel or nick
+    SayTo(String, String),
+    Join(String),
+    Part(String, Option<String>),
+    Nick(String),
+    Quit(Option<String>),
+}
+
+// One command invocation, as seen by Command::run
+pub struct Call<'a> {
+    pub caller: &'a Caller,
+    // channel the command was sent to
+    pub channel: &'a str,
+    // arguments after the command name
+    pub args: &'a [String],
+    pub commands: &'a Commands,
+}
+
+/// A command run with `<prefix> <name> args...` on irc
+///
+/// Commands run on the database worker thread, and describe what to
+/// send back as Actions instead of talking to irc themselves.
+pub trait Command: Send + Sync {
+    fn name(&self) -> &'static str;
+    // arguments, as shown in help
+    fn usage(&self) -> &'static str {
+        ""
+    }
+    // one line description
+    fn help(&self) -> &'static str;
+    fn level(&self) -> Level {
+        Level::Anyone
+    }
+    fn min_args(&self) -> usize {
+        0
+    }
+    fn max_args(&self) -> Option<usize> {
+        None
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>>;
+}
+
+// Registry of commands, looked up by name after the prefix
+pub struct Commands {
+    prefix: String,
+    commands: Vec<Box<dyn Command>>,
+    // nick!user@host globs allowed to run admin commands
+    admins: Vec<String>,
+}
+
+impl Commands {
+    pub fn new(prefix: &str, admins: Vec<String>) -> Commands {
+        Commands { prefix: prefix.to_string(), commands: vec![], admins }
+    }
+
+    // the built in commands, with prefix and admins from the config options
+    pub fn from_irc_config(config: &Config) -> Result<Commands> {
+        let prefix = config.options.get("command_prefix").map(AsRef::as_ref)
+            .unwrap_or(DEFAULT_PREFIX);
+        let admins = match config.options.get("admins") {
+            Some(admins) => hostmask::parse_masks("admins", admins)?,
+            None => vec![]
+        };
+        let mut commands = Commands::new(prefix, admins);
+        commands.register(Box::new(Help));
+        commands.register(Box::new(Stats));
+        commands.register(Box::new(Complete));
+        commands.register(Box::new(Learn));
+        commands.register(Box::new(Forget));
+        commands.register(Box::new(Say));
+        commands.register(Box::new(Join));
+        commands.register(Box::new(Part));
+        commands.register(Box::new(Nick));
+        commands.register(Box::new(Quit));
+        Ok(commands)
+    }
+
+    // Admin if mask matches one of the admins globs
+    pub fn level(&self, mask: &str) -> Level {
+        if self.admins.iter().any(|admin| hostmask::glob_match(admin, mask)) {
+            Level::Admin
+        } else {
+            Level::Anyone
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // add a command, replacing any with the same name
+    pub fn register(&mut self, command: Box<dyn Command>) {
+        self.commands.retain(|c| c.name() != command.name());
+        self.commands.push(command);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&dyn Command> {
+        let name = name.to_lowercase();
+        self.commands.iter().find(|c| c.name() == name).map(AsRef::as_ref)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.iter().map(AsRef::as_ref)
+    }
+
+    // words after the prefix, or None if text isn't a command
+    pub fn parse(&self, text: &str) -> Option<Vec<String>> {
+        let text = text.trim_start();
+        if !text.starts_with(&self.prefix) {
+            return None;
+        }
+        let rest = &text[self.prefix.len()..];
+        match rest.chars().next() {
+            None => Some(vec![]),
+            Some(c) if c.is_whitespace() => Some(split_args(rest)),
+            // !bazooka isn't for us
+            Some(_) => None
+        }
+    }
+
+    /// Run the command named by the first word, checking the caller's
+    /// level and the number of arguments first
+    pub fn run(&self, words: &mut WordsDb, caller: &Caller, channel: &str, line: &[String])
+        -> Result<Vec<Action>> {
+        let (name, args) = match line.split_first() {
+            Some((name, args)) => (name, args),
+            None => return Ok(vec![Action::Say(self.summary())])
+        };
+        let command = match self.find(name) {
+            Some(command) => command,
+            None => return Ok(vec![Action::Say(
+                format!("Unknown command {}, try {} help", name, self.prefix))])
+        };
+        if caller.level < command.level() {
+            warn!("Refused {} {} from {} on {}", self.prefix, name, caller.mask, channel);
+            return Ok(vec![Action::Say(format!("{}: {} is for admins", caller.nick, command.name()))]);
+        }
+        let too_many = match command.max_args() {
+            Some(max) => args.len() > max,
+            None => false
+        };
+        if args.len() < command.min_args() || too_many {
+            return Ok(vec![Action::Say(self.usage(command))]);
+        }
+        command.run(words, &Call { caller, channel, args, commands: self })
+    }
+
+    fn usage(&self, command: &dyn Command) -> String {
+        if command.usage().is_empty() {
+            format!("usage: {} {}", self.prefix, command.name())
+        } else {
+            format!("usage: {} {} {}", self.prefix, command.name(), command.usage())
+        }
+    }
+
+    fn summary(&self) -> String {
+        let names: Vec<&str> = self.iter().map(|c| c.name()).collect();
+        format!("commands: {} (try {} help COMMAND)", names.join(", "), self.prefix)
+    }
+}
+
+// split on whitespace, keeping "quoted words" together
+pub fn split_args(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut quoted = false;
+    let mut in_arg = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(arg.split_off(0));
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
+struct Help;
+
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+    fn usage(&self) -> &'static str {
+        "[COMMAND]"
+    }
+    fn help(&self) -> &'static str {
+        "list commands, or describe one"
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, _words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let commands = call.commands;
+        let reply = match call.args.first() {
+            None => commands.summary(),
+            Some(name) => match commands.find(name) {
+                Some(command) => {
+                    let admin = if command.level() == Level::Admin { " (admins only)" } else { "" };
+                    format!("{} - {}{}", commands.usage(command), command.help(), admin)
+                }
+                None => format!("Unknown command {}", name)
+            }
+        };
+        Ok(vec![Action::Say(reply)])
+    }
+}
+
+struct Stats;
+
+impl Command for Stats {
+    fn name(&self) -> &'static str {
+        "stats"
+    }
+    fn help(&self) -> &'static str {
+        "today's activity on this channel"
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(0)
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let today = words.stats_history(1, Some(call.channel))?;
+        // reconnects are counted per server, never per channel
+        let counts: Vec<String> = COUNTERS.iter()
+            .filter(|c| **c != Counter::Reconnects)
+            .map(|c| format!("{} {}", c, today.first().map(|t| t.get(*c)).unwrap_or(0)))
+            .collect();
+        Ok(vec![Action::Say(format!("today on {}: {}", call.channel, counts.join(", ")))])
+    }
+}
+
+struct Complete;
+
+impl Command for Complete {
+    fn name(&self) -> &'static str {
+        "complete"
+    }
+    fn usage(&self) -> &'static str {
+        "WORDS..."
+    }
+    fn help(&self) -> &'static str {
+        "complete around _, or fill in each _ of a template"
+    }
+    fn min_args(&self) -> usize {
+        1
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let phrase = words.complete_args(call.args)?;
+        Ok(vec![Action::Say(markov_words::join_phrase(vec![], phrase))])
+    }
+}
+
+// turn learning on or off until the bot restarts
+struct Learn;
+
+impl Command for Learn {
+    fn name(&self) -> &'static str {
+        "learn"
+    }
+    fn usage(&self) -> &'static str {
+        "[on|off]"
+    }
+    fn help(&self) -> &'static str {
+        "show or switch learning from irc"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        match call.args.first().map(AsRef::as_ref) {
+            Some("on") => words.set_learning(true)?,
+            Some("off") => words.set_learning(false)?,
+            Some(other) => return Ok(vec![Action::Say(format!("learn on or off, not {}", other))]),
+            None => ()
+        }
+        if !call.args.is_empty() {
+            info!("{} set learning {}", call.caller.mask, call.args[0]);
+        }
+        let state = if words.learning() { "on" } else { "off" };
+        Ok(vec![Action::Say(format!("learning is {}", state))])
+    }
+}
+
+struct Forget;
+
+impl Command for Forget {
+    fn name(&self) -> &'static str {
+        "forget"
+    }
+    fn usage(&self) -> &'static str {
+        "WORD"
+    }
+    fn help(&self) -> &'static str {
+        "forget a word and every phrase using it"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn min_args(&self) -> usize {
+        1
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let word = &call.args[0];
+        let forgotten = words.forget_word(word)?;
+        info!("{} made me forget {} ({} phrases)", call.caller.mask, word, forgotten);
+        Ok(vec![Action::Say(format!("forgot {} phrases with {}", forgotten, word))])
+    }
+}
+
+struct Say;
+
+impl Command for Say {
+    fn name(&self) -> &'static str {
+        "say"
+    }
+    fn usage(&self) -> &'static str {
+        "TARGET TEXT..."
+    }
+    fn help(&self) -> &'static str {
+        "say something on a channel or to a nick"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn min_args(&self) -> usize {
+        2
+    }
+    fn run(&self, _words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        Ok(vec![Action::SayTo(call.args[0].clone(), call.args[1..].join(" "))])
+    }
+}
+
+struct Join;
+
+impl Command for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+    fn usage(&self) -> &'static str {
+        "CHANNEL"
+    }
+    fn help(&self) -> &'static str {
+        "join a channel"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn min_args(&self) -> usize {
+        1
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, _words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        info!("{} asked me to join {}", call.caller.mask, call.args[0]);
+        Ok(vec![Action::Join(call.args[0].clone())])
+    }
+}
+
+struct Part;
+
+impl Command for Part {
+    fn name(&self) -> &'static str {
+        "part"
+    }
+    fn usage(&self) -> &'static str {
+        "[CHANNEL] [MESSAGE...]"
+    }
+    fn help(&self) -> &'static str {
+        "leave a channel, this one by default"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn run(&self, _words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let (channel, message) = match call.args.split_first() {
+            Some((channel, message)) if channel.starts_with('#') || channel.starts_with('&') =>
+                (channel.clone(), message),
+            _ => (call.channel.to_string(), call.args)
+        };
+        let message = if message.is_empty() { None } else { Some(message.join(" ")) };
+        info!("{} asked me to part {}", call.caller.mask, channel);
+        Ok(vec![Action::Part(channel, message)])
+    }
+}
+
+struct Nick;
+
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+    fn usage(&self) -> &'static str {
+        "NICK"
+    }
+    fn help(&self) -> &'static str {
+        "change the bot's nick"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn min_args(&self) -> usize {
+        1
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, _words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        info!("{} changed my nick to {}", call.caller.mask, call.args[0]);
+        Ok(vec![Action::Nick(call.args[0].clone())])
+    }
+}
+
+struct Quit;
+
+impl Command for Quit {
+    fn name(&self) -> &'static str {
+        "quit"
+    }
+    fn usage(&self) -> &'static str {
+        "[MESSAGE...]"
+    }
+    fn help(&self) -> &'static str {
+        "disconnect and stop the bot"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn run(&self, _words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        info!("{} asked me to quit", call.caller.mask);
+        let message = if call.args.is_empty() { None } else { Some(call.args.join(" ")) };
+        Ok(vec![Action::Quit(message)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+
+    fn words() -> WordsDb {
+        let w = WordsDb::new(":memory:".to_string()).expect("open");
+        w.migrate().expect("migrate");
+        w.add_line("the cat sat").expect("add");
+        w
+    }
+
+    fn line(text: &str) -> Vec<String> {
+        split_args(text)
+    }
+
+    fn say(actions: Result<Vec<Action>>) -> String {
+        match actions.expect("run").as_slice() {
+            [Action::Say(text)] => text.clone(),
+            other => panic!("expected one reply, got {:?}", other)
+        }
+    }
+
+    fn caller(nick: &str, level: Level) -> Caller {
+        Caller { nick: nick.to_string(), mask: format!("{}!{}@host", nick, nick), level }
+    }
+
+    struct Secret;
+    impl Command for Secret {
+        fn name(&self) -> &'static str {
+            "secret"
+        }
+        fn help(&self) -> &'static str {
+            "admins only"
+        }
+        fn level(&self) -> Level {
+            Level::Admin
+        }
+        fn run(&self, _words: &mut WordsDb, _call: &Call) -> Result<Vec<Action>> {
+            Ok(vec![Action::Say("shh".to_string())])
+        }
+    }
+
+    #[test]
+    fn parse_prefix() {
+        let commands = Commands::new("!baz", vec![]);
+        assert_eq!(Some(vec!["help".to_string()]), commands.parse("!baz help"));
+        assert_eq!(Some(vec![]), commands.parse("  !baz"));
+        assert_eq!(None, commands.parse("!bazooka help"));
+        assert_eq!(None, commands.parse("hey !baz help"));
+    }
+
+    #[test]
+    fn split_quoted() {
+        assert_eq!(vec!["say", "#chan", "hello there", ""], split_args(r#" say  #chan "hello there" """#));
+    }
+
+    #[test]
+    fn run_commands() {
+        let mut w = words();
+        let mut commands = Commands::from_irc_config(&Config::default()).unwrap();
+        commands.register(Box::new(Secret));
+        let anyone = caller("joe", Level::Anyone);
+        let admin = caller("root", Level::Admin);
+
+        assert!(say(commands.run(&mut w, &anyone, "#a", &[])).starts_with("commands: help, stats, complete, learn"));
+        assert_eq!("usage: !baz complete WORDS... - complete around _, or fill in each _ of a template",
+                   say(commands.run(&mut w, &anyone, "#a", &line("help complete"))));
+        assert_eq!("usage: !baz complete WORDS...", say(commands.run(&mut w, &anyone, "#a", &line("complete"))));
+        assert_eq!("the cat sat", say(commands.run(&mut w, &anyone, "#a", &line("complete the _ sat"))));
+        assert_eq!("Unknown command nope, try !baz help", say(commands.run(&mut w, &anyone, "#a", &line("nope"))));
+        assert_eq!("joe: secret is for admins", say(commands.run(&mut w, &anyone, "#a", &line("secret"))));
+        assert_eq!("shh", say(commands.run(&mut w, &admin, "#a", &line("SECRET"))));
+        assert_eq!("today on #a: lines 0, words 0, trigrams 0, replies 0, errors 0, limited 0",
+                   say(commands.run(&mut w, &anyone, "#a", &line("stats"))));
+    }
+
+    #[test]
+    fn admin_levels() {
+        let commands = Commands::from_irc_config(&irc_config(&[("admins", "root!*@*.example.com")])).unwrap();
+        assert_eq!(Level::Admin, commands.level("root!~r@box.example.com"));
+        assert_eq!(Level::Anyone, commands.level("root!~r@elsewhere.org"));
+    }
+
+    #[test]
+    fn admin_commands() {
+        let mut w = words();
+        let commands = Commands::from_irc_config(&Config::default()).unwrap();
+        let anyone = caller("joe", Level::Anyone);
+        let admin = caller("root", Level::Admin);
+        let run = |w: &mut WordsDb, caller: &Caller, text: &str| commands.run(w, caller, "#a", &line(text)).expect("run");
+
+        assert_eq!(vec![Action::Say("joe: quit is for admins".to_string())], run(&mut w, &anyone, "quit"));
+        assert_eq!(vec![Action::Say("learning is off".to_string())], run(&mut w, &admin, "learn off"));
+        w.add_line("the dog sat").expect("not learned");
+        assert_eq!(None, w.get_word_id("dog").unwrap());
+        assert_eq!(vec![Action::Say("learning is on".to_string())], run(&mut w, &admin, "learn on"));
+        assert_eq!(vec![Action::Say("forgot 3 phrases with cat".to_string())], run(&mut w, &admin, "forget cat"));
+        let phrase = w.complete_args(&line("the _")).unwrap_or_default();
+        assert!(!phrase.contains(&"cat".to_string()));
+        assert_eq!(vec![Action::SayTo("#b".to_string(), "hi there".to_string())], run(&mut w, &admin, "say #b hi there"));
+        assert_eq!(vec![Action::Join("#b".to_string())], run(&mut w, &admin, "join #b"));
+        assert_eq!(vec![Action::Part("#a".to_string(), Some("bye now".to_string()))], run(&mut w, &admin, "part bye now"));
+        assert_eq!(vec![Action::Part("#b".to_string(), None)], run(&mut w, &admin, "part #b"));
+        assert_eq!(vec![Action::Nick("baz2".to_string())], run(&mut w, &admin, "nick baz2"));
+        assert_eq!(vec![Action::Quit(None)], run(&mut w, &admin, "quit"));
+    }
+}
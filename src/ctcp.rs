@@ -0,0 +1,80 @@
+// CTCP messages are PRIVMSGs wrapped in \x01, replies go back as NOTICEs
+
+const DELIM: char = '\u{1}';
+
+pub const VERSION: &str = concat!("bazbot ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, PartialEq)]
+pub enum Ctcp<'a> {
+    // /me text
+    Action(&'a str),
+    Version,
+    // echoed back
+    Ping(&'a str),
+    Time,
+    // not answered
+    Other(&'a str),
+}
+
+// None for plain messages, the closing \x01 is optional
+pub fn parse(text: &str) -> Option<Ctcp<'_>> {
+    if !text.starts_with(DELIM) {
+        return None;
+    }
+    let body = text[1..].trim_end_matches(DELIM);
+    let mut parts = body.splitn(2, ' ');
+    let command = parts.next().unwrap_or("");
+    let args = parts.next().unwrap_or("");
+    Some(match command.to_uppercase().as_str() {
+        "ACTION" => Ctcp::Action(args),
+        "VERSION" => Ctcp::Version,
+        "PING" => Ctcp::Ping(args),
+        "TIME" => Ctcp::Time,
+        _ => Ctcp::Other(command)
+    })
+}
+
+fn wrap(body: &str) -> String {
+    format!("{}{}{}", DELIM, body, DELIM)
+}
+
+/// The NOTICE answering a query, None for actions and unknown queries
+pub fn reply(query: &Ctcp, now: &str) -> Option<String> {
+    match query {
+        Ctcp::Version => Some(wrap(&format!("VERSION {}", VERSION))),
+        Ctcp::Ping(args) => Some(wrap(&format!("PING {}", args))),
+        Ctcp::Time => Some(wrap(&format!("TIME {}", now))),
+        Ctcp::Action(_) | Ctcp::Other(_) => None
+    }
+}
+
+// a PRIVMSG body that shows as /me text
+pub fn action(text: &str) -> String {
+    wrap(&format!("ACTION {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ctcp() {
+        assert_eq!(Some(Ctcp::Action("waves at joel")), parse("\u{1}ACTION waves at joel\u{1}"));
+        assert_eq!(Some(Ctcp::Action("waves")), parse("\u{1}ACTION waves"));
+        assert_eq!(Some(Ctcp::Version), parse("\u{1}version\u{1}"));
+        assert_eq!(Some(Ctcp::Ping("1234 5")), parse("\u{1}PING 1234 5\u{1}"));
+        assert_eq!(Some(Ctcp::Other("FINGER")), parse("\u{1}FINGER\u{1}"));
+        assert_eq!(None, parse("hello \u{1}ACTION\u{1}"));
+    }
+
+    #[test]
+    fn replies() {
+        let now = "Mon, 19 Oct 2026 12:00:00 +0000";
+        assert_eq!(Some(format!("\u{1}VERSION bazbot {}\u{1}", env!("CARGO_PKG_VERSION"))),
+                   reply(&Ctcp::Version, now));
+        assert_eq!(Some("\u{1}PING 1234\u{1}".to_string()), reply(&Ctcp::Ping("1234"), now));
+        assert_eq!(Some(format!("\u{1}TIME {}\u{1}", now)), reply(&Ctcp::Time, now));
+        assert_eq!(None, reply(&Ctcp::Action("waves"), now));
+        assert_eq!("\u{1}ACTION waves\u{1}", action("waves"));
+    }
+}
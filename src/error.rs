@@ -0,0 +1,72 @@
+use std::{fmt, io};
+
+// Errors surfaced by the bot, grouped by what the caller can do about them
+#[derive(Debug)]
+pub enum Error {
+    // sqlite failed, or the database is not in the expected shape
+    Storage(rusqlite::Error),
+    // the database schema doesn't match this binary's migrations
+    Migration(String),
+    // config file is missing, unreadable or has a bad option
+    Config(String),
+    // asked to learn phrases with learn = false
+    LearningDisabled,
+    // reading input files
+    Io(io::Error),
+    // no phrase could be generated that meets the constraints
+    Unsatisfiable(String),
+    // the thread owning the database has stopped
+    WorkerGone,
+    // connecting to or talking with the irc server
+    Irc(irc::error::Error),
+    // check found problems that weren't repaired
+    Integrity(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Storage(e) => write!(f, "database error: {}", e),
+            Error::Migration(msg) => write!(f, "migration error: {}", msg),
+            Error::Config(msg) => write!(f, "configuration error: {}", msg),
+            Error::LearningDisabled => write!(f, "learning phrases is disabled by config"),
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Unsatisfiable(msg) => write!(f, "couldn't generate phrase: {}", msg),
+            Error::WorkerGone => write!(f, "database worker has stopped"),
+            Error::Irc(e) => write!(f, "irc error: {}", e),
+            Error::Integrity(msg) => write!(f, "database integrity problem: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Irc(e) => Some(e),
+            Error::Migration(_) | Error::Config(_) | Error::LearningDisabled
+                | Error::Unsatisfiable(_) | Error::WorkerGone | Error::Integrity(_) => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Storage(e)
+    }
+}
+
+impl From<irc::error::Error> for Error {
+    fn from(e: irc::error::Error) -> Error {
+        Error::Irc(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
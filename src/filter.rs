@@ -0,0 +1,201 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use regex::Regex;
+use std::fmt;
+
+use crate::error::{Error, Result};
+use crate::options::parse_option;
+
+const DEFAULT_MIN_TOKENS: usize = 2;
+const DEFAULT_MAX_TOKENS: usize = 50;
+const DEFAULT_NON_TEXT: f64 = 0.5;
+// a token this long without spaces is more likely base64 or hex than a word
+const BLOB_LEN: usize = 32;
+
+// Why a line wasn't learned
+#[derive(Debug, PartialEq)]
+pub enum Rejected {
+    TooShort,
+    TooLong,
+    Prefix(String),
+    Code,
+    Denied(String),
+    NonText,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejected::TooShort => write!(f, "too short"),
+            Rejected::TooLong => write!(f, "too long"),
+            Rejected::Prefix(prefix) => write!(f, "starts with {}", prefix),
+            Rejected::Code => write!(f, "looks like code"),
+            Rejected::Denied(regex) => write!(f, "matches {}", regex),
+            Rejected::NonText => write!(f, "mostly not text")
+        }
+    }
+}
+
+// Which lines are worth learning, from irc or read from files
+#[derive(Debug)]
+pub struct LearnFilter {
+    // bounds on tokens left after urls are dropped
+    pub min_tokens: usize,
+    pub max_tokens: usize,
+    // skip lines whose first token starts with one of these
+    pub prefixes: Vec<String>,
+    // drop url tokens
+    pub urls: bool,
+    // skip code and base64 or hex blobs
+    pub code: bool,
+    // skip lines matching any of these
+    pub deny: Vec<Regex>,
+    // skip lines with more than this fraction of non letter characters
+    pub non_text: f64,
+}
+
+impl Default for LearnFilter {
+    fn default() -> LearnFilter {
+        LearnFilter {
+            min_tokens: DEFAULT_MIN_TOKENS,
+            max_tokens: DEFAULT_MAX_TOKENS,
+            prefixes: vec!["!".to_string()],
+            urls: true,
+            code: true,
+            deny: vec![],
+            non_text: DEFAULT_NON_TEXT
+        }
+    }
+}
+
+fn is_url(token: &str) -> bool {
+    let lower = token.to_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://") || lower.starts_with("www.")
+        || lower.starts_with("ftp://")
+}
+
+fn is_code(token: &str) -> bool {
+    const MARKS: [&str; 8] = ["::", "=>", "->", "==", "&&", "||", "()", "</"];
+    token.ends_with(';') || token == "{" || token == "}" || token.ends_with('{')
+        || MARKS.iter().any(|m| token.contains(m))
+}
+
+fn is_blob(token: &str) -> bool {
+    token.len() >= BLOB_LEN
+        && token.chars().any(|c| c.is_ascii_digit())
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=')
+}
+
+impl LearnFilter {
+    pub fn from_irc_config(config: &Config) -> Result<LearnFilter> {
+        let default = LearnFilter::default();
+        let prefixes = match config.options.get("filter_prefixes") {
+            Some(list) => list.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|p| !p.is_empty())
+                .map(ToString::to_string)
+                .collect(),
+            None => default.prefixes
+        };
+        let mut deny = vec![];
+        if let Some(list) = config.options.get("filter_deny") {
+            for pattern in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                deny.push(Regex::new(pattern).map_err(|e| {
+                    Error::Config(format!("Bad filter_deny regex {}: {}", pattern, e))
+                })?);
+            }
+        }
+        let non_text = parse_option(config, "filter_non_text", "a fraction", default.non_text)?;
+        if !(0.0..=1.0).contains(&non_text) {
+            return Err(Error::Config(format!("filter_non_text should be from 0 to 1, not {}", non_text)));
+        }
+        Ok(LearnFilter {
+            min_tokens: parse_option(config, "filter_min_tokens", "a count", default.min_tokens)?,
+            max_tokens: parse_option(config, "filter_max_tokens", "a count", default.max_tokens)?,
+            prefixes,
+            urls: parse_option(config, "filter_urls", "bool", default.urls)?,
+            code: parse_option(config, "filter_code", "bool", default.code)?,
+            deny,
+            non_text
+        })
+    }
+
+    /// The tokens of phrase worth learning, without urls, or why the
+    /// whole line should be skipped
+    pub fn clean<'a>(&self, phrase: &[&'a str]) -> std::result::Result<Vec<&'a str>, Rejected> {
+        if let Some(first) = phrase.first() {
+            if let Some(prefix) = self.prefixes.iter().find(|p| first.starts_with(p.as_str())) {
+                return Err(Rejected::Prefix(prefix.clone()));
+            }
+        }
+        let line = phrase.join(" ");
+        if let Some(regex) = self.deny.iter().find(|r| r.is_match(&line)) {
+            return Err(Rejected::Denied(regex.as_str().to_string()));
+        }
+        let tokens: Vec<&str> = phrase.iter().cloned()
+            .filter(|t| !(self.urls && is_url(t)))
+            .collect();
+        if tokens.len() < self.min_tokens {
+            return Err(Rejected::TooShort);
+        }
+        if tokens.len() > self.max_tokens {
+            return Err(Rejected::TooLong);
+        }
+        if self.code {
+            let code = tokens.iter().filter(|t| is_code(t)).count();
+            if tokens.iter().any(|t| is_blob(t)) || (code >= 2 && code * 4 >= tokens.len()) {
+                return Err(Rejected::Code);
+            }
+        }
+        let chars = tokens.iter().flat_map(|t| t.chars());
+        let (letters, total) = chars.fold((0, 0), |(l, n), c| (l + usize::from(c.is_alphabetic()), n + 1));
+        if total > 0 && (total - letters) as f64 / total as f64 > self.non_text {
+            return Err(Rejected::NonText);
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+
+    fn clean(filter: &LearnFilter, line: &str) -> std::result::Result<Vec<String>, Rejected> {
+        let phrase: Vec<&str> = line.split_whitespace().collect();
+        filter.clean(&phrase).map(|tokens| tokens.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn default_rules() {
+        let filter = LearnFilter::default();
+        assert_eq!(Ok(vec!["the".to_string(), "cat".to_string(), "sat".to_string()]), clean(&filter, "the cat sat"));
+        assert_eq!(Err(Rejected::TooShort), clean(&filter, "lol"));
+        assert_eq!(Err(Rejected::TooShort), clean(&filter, "https://example.com/x"));
+        assert_eq!(Ok(vec!["see".to_string(), "this".to_string()]), clean(&filter, "see https://example.com this"));
+        assert_eq!(Err(Rejected::Prefix("!".to_string())), clean(&filter, "!weather paris"));
+        assert_eq!(Err(Rejected::Code), clean(&filter, "fn main() { println!(\"hi\"); }"));
+        assert_eq!(Err(Rejected::Code), clean(&filter, "key: aGVsbG8gd29ybGQgdGhpcyBpcyBiYXNlNjQgZGF0YQ1="));
+        assert_eq!(Err(Rejected::NonText), clean(&filter, "^^ :) :) 42"));
+        assert_eq!(Err(Rejected::TooLong), clean(&filter, &"word ".repeat(51)));
+        assert!(clean(&filter, "I think foo() is wrong").is_ok());
+    }
+
+    #[test]
+    fn parse_config() {
+        let filter = LearnFilter::from_irc_config(&irc_config(&[
+            ("filter_min_tokens", "1"), ("filter_max_tokens", "3"), ("filter_prefixes", "., @"),
+            ("filter_urls", "false"), ("filter_code", "false"), ("filter_deny", "^\\[bot\\], spam"),
+            ("filter_non_text", "1")])).unwrap();
+        assert!(clean(&filter, "lol").is_ok());
+        assert!(clean(&filter, "!weather").is_ok());
+        assert_eq!(Err(Rejected::Prefix(".".to_string())), clean(&filter, ".seen joe"));
+        assert_eq!(Err(Rejected::TooLong), clean(&filter, "a b c d"));
+        assert_eq!(1, clean(&filter, "http://x.y").unwrap().len());
+        assert!(clean(&filter, "x = y;").is_ok());
+        assert_eq!(Err(Rejected::Denied("spam".to_string())), clean(&filter, "buy spam now"));
+        assert!(clean(&filter, "[bot] says").is_err());
+        for bad in &[("filter_min_tokens", "two"), ("filter_non_text", "2"), ("filter_deny", "(")] {
+            assert!(LearnFilter::from_irc_config(&irc_config(&[*bad])).is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+extern crate irc;
+use self::irc::client::prelude::Prefix;
+
+use crate::error::{Error, Result};
+
+// nick!user@host of a message's sender, None for server messages
+pub fn mask(prefix: &Prefix) -> Option<String> {
+    match prefix {
+        Prefix::Nickname(nick, user, host) => Some(format!("{}!{}@{}", nick, user, host)),
+        Prefix::ServerName(_) => None
+    }
+}
+
+// case insensitive glob, * matches any run of characters and ? any one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where the last * was, and the text position it's matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the * swallow one more character
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// split a list of nick!user@host globs, from a config option
+pub fn parse_masks(option: &str, masks: &str) -> Result<Vec<String>> {
+    let masks: Vec<String> = masks.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|m| !m.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    match masks.iter().find(|m| !m.contains('!') || !m.contains('@')) {
+        Some(bad) => Err(Error::Config(
+            format!("{} should be nick!user@host masks, not {}", option, bad))),
+        None => Ok(masks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("joel!*@*.example.com", "Joel!~joel@home.example.com"));
+        assert!(glob_match("*!*@host", "a!b@host"));
+        assert!(glob_match("j?el!*@*", "joel!x@y"));
+        assert!(!glob_match("joel!*@*.example.com", "joel!~joel@example.com"));
+        assert!(!glob_match("joel!*@*", "joely!x@y"));
+        assert!(glob_match("**", ""));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(vec!["a!*@*", "*!*@b"], parse_masks("admins", "a!*@*, *!*@b").unwrap());
+        assert!(parse_masks("admins", "joel").is_err());
+    }
+
+    #[test]
+    fn prefix_mask() {
+        let prefix = Prefix::Nickname("joel".into(), "~j".into(), "host".into());
+        assert_eq!(Some("joel!~j@host".to_string()), mask(&prefix));
+    }
+}
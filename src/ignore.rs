@@ -0,0 +1,256 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use regex::Regex;
+use std::sync::{Arc, RwLock};
+
+use crate::commands::{Action, Call, Command, Level};
+use crate::error::{Error, Result};
+use crate::hostmask;
+use crate::markov_words::WordsDb;
+
+// How a rule is matched against a sender
+#[derive(Debug)]
+enum Matcher {
+    // glob against the nick
+    Nick(String),
+    // glob against nick!user@host
+    Mask(String),
+    // search in nick!user@host
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    text: String,
+    matcher: Matcher,
+    // rules from the config can't be removed at runtime
+    from_config: bool,
+}
+
+impl Rule {
+    /// Parse a rule: /regex/ is searched for in nick!user@host, a rule
+    /// with ! or @ is a hostmask glob, anything else is a nick glob
+    fn parse(text: &str, from_config: bool) -> Result<Rule> {
+        let text = text.trim();
+        let matcher = if text.len() > 1 && text.starts_with('/') && text.ends_with('/') {
+            let pattern = &text[1..text.len() - 1];
+            Matcher::Regex(Regex::new(pattern).map_err(|e| {
+                Error::Config(format!("Bad ignore regex {}: {}", text, e))
+            })?)
+        } else if text.contains('!') || text.contains('@') {
+            Matcher::Mask(text.to_string())
+        } else if !text.is_empty() {
+            Matcher::Nick(text.to_string())
+        } else {
+            return Err(Error::Config("Empty ignore rule".to_string()));
+        };
+        Ok(Rule { text: text.to_string(), matcher, from_config })
+    }
+
+    fn matches(&self, nick: &str, mask: &str) -> bool {
+        match &self.matcher {
+            Matcher::Nick(glob) => hostmask::glob_match(glob, nick),
+            Matcher::Mask(glob) => hostmask::glob_match(glob, mask),
+            Matcher::Regex(regex) => regex.is_match(mask)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Removed {
+    Removed,
+    NotFound,
+    FromConfig,
+}
+
+// Senders the bot neither learns from nor answers, shared with the
+// ignore commands so changes apply straight away
+#[derive(Debug, Default)]
+pub struct Ignores {
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl Ignores {
+    // comma separated rules from the ignore option
+    pub fn from_irc_config(config: &Config) -> Result<Ignores> {
+        let ignores = Ignores::default();
+        if let Some(rules) = config.options.get("ignore") {
+            for rule in rules.split(',').filter(|r| !r.trim().is_empty()) {
+                ignores.insert(Rule::parse(rule, true)?);
+            }
+        }
+        Ok(ignores)
+    }
+
+    // rules stored in the database by earlier ignore commands
+    pub fn load(&self, words: &WordsDb) -> Result<()> {
+        for rule in words.ignore_rules()? {
+            match Rule::parse(&rule, false) {
+                Ok(rule) => self.insert(rule),
+                Err(e) => warn!("Skipping stored ignore rule: {}", e)
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&self, rule: Rule) {
+        let mut rules = self.rules.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !rules.iter().any(|r| r.text == rule.text) {
+            rules.push(rule);
+        }
+    }
+
+    // the first rule matching the sender, if any
+    pub fn matching(&self, nick: &str, mask: &str) -> Option<String> {
+        let rules = self.rules.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        rules.iter().find(|r| r.matches(nick, mask)).map(|r| r.text.clone())
+    }
+
+    pub fn rules(&self) -> Vec<String> {
+        let rules = self.rules.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        rules.iter().map(|r| r.text.clone()).collect()
+    }
+
+    fn remove(&self, text: &str) -> Removed {
+        let mut rules = self.rules.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match rules.iter().position(|r| r.text == text) {
+            Some(pos) if rules[pos].from_config => Removed::FromConfig,
+            Some(pos) => {
+                rules.remove(pos);
+                Removed::Removed
+            }
+            None => Removed::NotFound
+        }
+    }
+}
+
+// !baz ignore RULE, stored in the database
+pub struct Ignore(pub Arc<Ignores>);
+
+impl Command for Ignore {
+    fn name(&self) -> &'static str {
+        "ignore"
+    }
+    fn usage(&self) -> &'static str {
+        "[NICK|NICK!USER@HOST|/REGEX/]"
+    }
+    fn help(&self) -> &'static str {
+        "stop learning from and answering someone, or list who's ignored"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let text = match call.args.first() {
+            Some(text) => text,
+            None => {
+                let rules = self.0.rules();
+                let reply = if rules.is_empty() {
+                    "ignoring nobody".to_string()
+                } else {
+                    format!("ignoring: {}", rules.join(", "))
+                };
+                return Ok(vec![Action::Say(reply)]);
+            }
+        };
+        let rule = match Rule::parse(text, false) {
+            Ok(rule) => rule,
+            Err(e) => return Ok(vec![Action::Say(e.to_string())])
+        };
+        words.add_ignore_rule(&rule.text, &call.caller.mask)?;
+        info!("{} added ignore rule {}", call.caller.mask, rule.text);
+        let reply = format!("ignoring {}", rule.text);
+        self.0.insert(rule);
+        Ok(vec![Action::Say(reply)])
+    }
+}
+
+pub struct Unignore(pub Arc<Ignores>);
+
+impl Command for Unignore {
+    fn name(&self) -> &'static str {
+        "unignore"
+    }
+    fn usage(&self) -> &'static str {
+        "RULE"
+    }
+    fn help(&self) -> &'static str {
+        "remove an ignore rule added with ignore"
+    }
+    fn level(&self) -> Level {
+        Level::Admin
+    }
+    fn min_args(&self) -> usize {
+        1
+    }
+    fn max_args(&self) -> Option<usize> {
+        Some(1)
+    }
+    fn run(&self, words: &mut WordsDb, call: &Call) -> Result<Vec<Action>> {
+        let text = call.args[0].trim();
+        let reply = match self.0.remove(text) {
+            Removed::Removed => {
+                words.remove_ignore_rule(text)?;
+                info!("{} removed ignore rule {}", call.caller.mask, text);
+                format!("no longer ignoring {}", text)
+            }
+            Removed::FromConfig => format!("{} is in the config file, remove it there", text),
+            Removed::NotFound => format!("no ignore rule {}", text)
+        };
+        Ok(vec![Action::Say(reply)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{Caller, Commands};
+    use crate::commands::split_args;
+    use crate::options::irc_config;
+
+    fn ignores(rules: &str) -> Ignores {
+        Ignores::from_irc_config(&irc_config(&[("ignore", rules)])).expect("parse")
+    }
+
+    #[test]
+    fn match_rules() {
+        let ignores = ignores("otherbot, relay*, *!*@bridge.example.com, /^gh-[0-9]+!/");
+        assert_eq!(Some("otherbot".to_string()), ignores.matching("OtherBot", "OtherBot!b@host"));
+        assert_eq!(Some("relay*".to_string()), ignores.matching("relay2", "relay2!r@host"));
+        assert_eq!(Some("*!*@bridge.example.com".to_string()), ignores.matching("ann", "ann!a@bridge.example.com"));
+        assert_eq!(Some("/^gh-[0-9]+!/".to_string()), ignores.matching("gh-12", "gh-12!g@host"));
+        assert_eq!(None, ignores.matching("joe", "joe!j@otherbot"));
+        assert!(Ignores::from_irc_config(&irc_config(&[("ignore", "/(/")])).is_err());
+    }
+
+    #[test]
+    fn runtime_rules() {
+        let mut w = WordsDb::new(":memory:".to_string()).expect("open");
+        w.migrate().expect("migrate");
+        let ignores = Arc::new(ignores("otherbot"));
+        let mut commands = Commands::new("!baz", vec![]);
+        commands.register(Box::new(Ignore(ignores.clone())));
+        commands.register(Box::new(Unignore(ignores.clone())));
+        let admin = Caller { nick: "root".to_string(), mask: "root!r@host".to_string(), level: Level::Admin };
+        let mut run = |text: &str| match commands.run(&mut w, &admin, "#a", &split_args(text)).expect("run").as_slice() {
+            [Action::Say(reply)] => reply.clone(),
+            other => panic!("expected a reply, got {:?}", other)
+        };
+
+        assert_eq!("ignoring spammer", run("ignore spammer"));
+        assert!(ignores.matching("spammer", "spammer!s@host").is_some());
+        assert_eq!("ignoring: otherbot, spammer", run("ignore"));
+        assert_eq!("otherbot is in the config file, remove it there", run("unignore otherbot"));
+        assert_eq!("no longer ignoring spammer", run("unignore spammer"));
+        assert_eq!("no ignore rule spammer", run("unignore spammer"));
+        run("ignore *!*@bridge");
+
+        // stored rules come back on restart
+        let reloaded = Ignores::default();
+        reloaded.load(&w).expect("load");
+        assert_eq!(vec!["*!*@bridge"], reloaded.rules());
+    }
+}
@@ -0,0 +1,127 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::options::parse_option;
+
+const DEFAULT_COOLDOWN_SECS: u64 = 300;
+
+// How often the bot joins in without being mentioned
+#[derive(Debug, PartialEq)]
+pub struct InterjectConfig {
+    // chance per line, for channels without their own
+    pub chance: f64,
+    // per channel chance, by lowercase channel name
+    pub channels: HashMap<String, f64>,
+    // least time between interjections on a channel
+    pub cooldown: Duration,
+}
+
+pub(crate) fn parse_chance(option: &str, value: &str) -> Result<f64> {
+    match value.trim().parse::<f64>() {
+        Ok(chance) if (0.0..=1.0).contains(&chance) => Ok(chance),
+        _ => Err(Error::Config(format!("{} should be a chance from 0 to 1, not {}", option, value)))
+    }
+}
+
+impl InterjectConfig {
+    pub fn from_irc_config(config: &Config) -> Result<InterjectConfig> {
+        let chance = match config.options.get("interject") {
+            Some(c) => parse_chance("interject", c)?,
+            None => 0.0
+        };
+        let mut channels = HashMap::new();
+        if let Some(list) = config.options.get("interject_channels") {
+            // "#rust=0.05, #offtopic=0.2"
+            for entry in list.split(|c: char| c == ',' || c.is_whitespace()).filter(|e| !e.is_empty()) {
+                let mut parts = entry.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(channel), Some(c)) if !channel.is_empty() => {
+                        channels.insert(channel.to_lowercase(), parse_chance("interject_channels", c)?);
+                    }
+                    _ => return Err(Error::Config(
+                        format!("interject_channels should be #channel=chance, not {}", entry)))
+                }
+            }
+        }
+        let cooldown = Duration::from_secs(
+            parse_option(config, "interject_cooldown", "seconds", DEFAULT_COOLDOWN_SECS)?);
+        Ok(InterjectConfig { chance, channels, cooldown })
+    }
+
+    pub fn chance(&self, channel: &str) -> f64 {
+        self.channels.get(&channel.to_lowercase()).cloned().unwrap_or(self.chance)
+    }
+}
+
+// Decides when to interject, remembering the last time on each channel
+pub struct Interjector {
+    config: InterjectConfig,
+    last: Mutex<HashMap<String, Instant>>,
+}
+
+impl Interjector {
+    pub fn new(config: InterjectConfig) -> Interjector {
+        Interjector { config, last: Mutex::new(HashMap::new()) }
+    }
+
+    // roll is uniform in [0, 1), taken from rand outside of tests
+    pub fn should_interject(&self, channel: &str, now: Instant, roll: f64) -> bool {
+        if roll >= self.config.chance(channel) {
+            return false;
+        }
+        let mut last = match self.last.lock() {
+            Ok(last) => last,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let channel = channel.to_lowercase();
+        let cooling = match last.get(&channel) {
+            Some(at) => now.duration_since(*at) < self.config.cooldown,
+            None => false
+        };
+        if cooling {
+            return false;
+        }
+        last.insert(channel, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+
+    #[test]
+    fn parse_config() {
+        let config = InterjectConfig::from_irc_config(&irc_config(&[
+            ("interject", "0.01"), ("interject_channels", "#Rust=0.5, #quiet=0"),
+            ("interject_cooldown", "60")])).unwrap();
+        assert_eq!(0.5, config.chance("#rust"));
+        assert_eq!(0.0, config.chance("#quiet"));
+        assert_eq!(0.01, config.chance("#other"));
+        assert_eq!(Duration::from_secs(60), config.cooldown);
+        let off = InterjectConfig::from_irc_config(&irc_config(&[])).unwrap();
+        assert_eq!(0.0, off.chance("#any"));
+        for bad in &[("interject", "2"), ("interject_channels", "#a"), ("interject_cooldown", "soon")] {
+            assert!(InterjectConfig::from_irc_config(&irc_config(&[*bad])).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn chance_and_cooldown() {
+        let config = InterjectConfig::from_irc_config(&irc_config(&[
+            ("interject", "0.5"), ("interject_cooldown", "60")])).unwrap();
+        let interjector = Interjector::new(config);
+        let start = Instant::now();
+        assert!(!interjector.should_interject("#a", start, 0.7));
+        assert!(interjector.should_interject("#a", start, 0.1));
+        // cooling down, but other channels aren't
+        assert!(!interjector.should_interject("#a", start + Duration::from_secs(30), 0.1));
+        assert!(interjector.should_interject("#b", start + Duration::from_secs(30), 0.1));
+        assert!(interjector.should_interject("#a", start + Duration::from_secs(61), 0.1));
+    }
+}
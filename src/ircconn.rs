@@ -5,45 +5,163 @@ use self::irc::client::prelude::{
     Client,
     Command,
     Prefix,
-    Message
+    Message,
+    Response
 };
 use irc::error::Result;
 use futures::*;
 
-use std::cell::RefCell;
+use rand::random;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+use crate::backup::BackupConfig;
+use crate::blocklist::{Block, Unblock};
+use crate::commands::{Action, Caller, Commands, Level};
+use crate::ctcp::{self, Ctcp};
+use crate::error;
+use crate::hostmask;
+use crate::ignore::{Ignore, Ignores, Unignore};
+use crate::interject::{self, InterjectConfig, Interjector};
+use crate::nicks::{NickConfig, Nicks};
+use crate::query::{self, QueryConfig};
+use crate::ratelimit::{Decision, RateConfig, RateLimiter};
+use crate::reconnect::ReconnectConfig;
+use crate::stats::Counter;
 use crate::markov_words;
 use crate::markov_words::WordsDb;
-use futures::executor::block_on;
+use crate::worker::WordsWorker;
 // use self::irc::proto::prefix::Prefix::ServerName;
 
+// chance a reply or interjection is a /me action, when any are known
+const DEFAULT_ACTION_CHANCE: f64 = 0.1;
+
+// Bot behavior read from the config's options
+pub struct BotConfig {
+    pub backups: Option<BackupConfig>,
+    pub commands: Commands,
+    pub interject: InterjectConfig,
+    pub rates: RateConfig,
+    pub ignores: Arc<Ignores>,
+    pub query: QueryConfig,
+    pub nicks: NickConfig,
+    pub reconnect: ReconnectConfig,
+    pub action_chance: f64,
+}
+
+impl BotConfig {
+    pub fn from_irc_config(config: &Config) -> error::Result<BotConfig> {
+        let ignores = Arc::new(Ignores::from_irc_config(config)?);
+        let mut commands = Commands::from_irc_config(config)?;
+        commands.register(Box::new(Ignore(ignores.clone())));
+        commands.register(Box::new(Unignore(ignores.clone())));
+        commands.register(Box::new(Block));
+        commands.register(Box::new(Unblock));
+        Ok(BotConfig {
+            backups: BackupConfig::from_irc_config(config)?,
+            commands,
+            interject: InterjectConfig::from_irc_config(config)?,
+            rates: RateConfig::from_irc_config(config)?,
+            ignores,
+            query: QueryConfig::from_irc_config(config)?,
+            nicks: NickConfig::from_irc_config(config)?,
+            reconnect: ReconnectConfig::from_irc_config(config)?,
+            action_chance: match config.options.get("action_chance") {
+                Some(c) => interject::parse_chance("action_chance", c)?,
+                None => DEFAULT_ACTION_CHANCE
+            }
+        })
+    }
+}
+
+// a connection that stayed up this long starts the backoff over
+const STABLE_CONNECTION: Duration = Duration::from_secs(300);
+
 pub struct IrcConn {
-    words: RefCell<Box<WordsDb>>,
-    client: Client
+    // database calls go to a worker thread and run in spawned
+    // tasks, so the stream keeps answering PINGs while they work
+    words: WordsWorker,
+    client: Client,
+    // to connect again with
+    config: Config,
+    backups: Option<BackupConfig>,
+    // shared with command tasks on the worker
+    commands: Arc<Commands>,
+    interjector: Interjector,
+    limiter: RateLimiter,
+    ignores: Arc<Ignores>,
+    query: QueryConfig,
+    // channel members, shared with reply tasks
+    nicks: Arc<Nicks>,
+    reconnect: ReconnectConfig,
+    action_chance: f64,
+    // set by the quit command, so the bot doesn't come back
+    quitting: Arc<AtomicBool>
 }
 
 impl IrcConn {
-    pub fn new(words: WordsDb, client: Client) -> IrcConn {
+    // words may be shared with connections to other networks
+    pub fn new(words: WordsWorker, client: Client, config: Config, bot: BotConfig) -> IrcConn {
         IrcConn {
-            words: RefCell::new(Box::new(words)),
-            client
+            words,
+            client,
+            config,
+            backups: bot.backups,
+            commands: Arc::new(bot.commands),
+            interjector: Interjector::new(bot.interject),
+            limiter: RateLimiter::new(bot.rates),
+            ignores: bot.ignores,
+            query: bot.query,
+            nicks: Arc::new(Nicks::new(bot.nicks)),
+            reconnect: bot.reconnect,
+            action_chance: bot.action_chance,
+            quitting: Arc::new(AtomicBool::new(false))
         }
     }
 
-    pub fn new_from_config(words: WordsDb, config: Config) -> IrcConn {
-        let client = block_on(async {
-            Client::from_config(config).await.expect("Client from config")
-        });
+    pub async fn new_from_config(words: WordsDb, config: Config) -> error::Result<IrcConn> {
+        let bot = BotConfig::from_irc_config(&config)?;
+        IrcConn::connect(WordsWorker::spawn(words)?, config, bot).await
+    }
 
-        IrcConn {
-            words: RefCell::new(Box::new(words)),
-            client
-        }
+    // load the stored ignore rules and connect
+    pub async fn connect(words: WordsWorker, config: Config, bot: BotConfig) -> error::Result<IrcConn> {
+        let ignores = bot.ignores.clone();
+        words.call(move |words| ignores.load(words)).await?;
+        let mut attempt = 0;
+        let client = connect_with_backoff(&config, &bot.reconnect, &mut attempt).await;
+        Ok(IrcConn::new(words, client, config, bot))
     }
 
+    // stays connected, with the same database, until told to quit
     pub async fn run(&mut self) {
-        self.run_irc()
-            .await
-            .unwrap_or_else(|e| error!("Error running irc: {:?}", e));
+        if let Some(ref backups) = self.backups {
+            self.schedule_backups(backups.clone());
+        }
+        let server = self.config.server.clone().unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            let connected = Instant::now();
+            match self.run_irc().await {
+                Ok(()) => warn!("Disconnected from {}", server),
+                Err(e) => error!("Error running irc: {:?}", e)
+            }
+            if self.quitting.load(Ordering::SeqCst) {
+                info!("Quit {}", server);
+                return;
+            }
+            if connected.elapsed() >= STABLE_CONNECTION {
+                attempt = 0;
+            }
+            self.nicks.clear();
+            // configured channels are joined again once identified
+            wait_to_reconnect(&self.reconnect, &server, &mut attempt).await;
+            self.client = connect_with_backoff(&self.config, &self.reconnect, &mut attempt).await;
+            info!("Reconnected to {}, attempt {}", server, attempt);
+            record_stat(&self.words, server.clone(), Counter::Reconnects).await;
+        }
     }
 
     async fn run_irc(&mut self) -> Result<()>
@@ -60,8 +178,31 @@ impl IrcConn {
     fn handle_message(&self, msg: &Message) {
         debug!("Handle message: {:?}", msg);
         if let Some(ref prefix) = msg.prefix {
+            let nick = match prefix {
+                Prefix::Nickname(nick, _, _) => nick.as_str(),
+                Prefix::ServerName(_) => ""
+            };
+            let me = nick.eq_ignore_ascii_case(self.client.current_nickname());
             match msg.command {
-                Command::JOIN(_, _, _) => info!("join channel {:?}", msg),
+                Command::JOIN(ref channel, _, _) if me => {
+                    info!("join channel {:?}", msg);
+                    self.nicks.joined(channel);
+                }
+                Command::JOIN(ref channel, _, _) => self.nicks.join(channel, nick),
+                Command::PART(ref channel, _) if me => self.nicks.left(channel),
+                Command::PART(ref channel, _) => self.nicks.part(channel, nick),
+                Command::KICK(ref channel, ref kicked, _) => {
+                    if kicked.eq_ignore_ascii_case(self.client.current_nickname()) {
+                        self.nicks.left(channel);
+                    } else {
+                        self.nicks.part(channel, kicked);
+                    }
+                }
+                Command::QUIT(_) => self.nicks.quit(nick),
+                Command::NICK(ref new) => self.nicks.rename(nick, new),
+                // me = chan-type channel :names
+                Command::Response(Response::RPL_NAMREPLY, ref args) if args.len() >= 4 =>
+                    self.nicks.names(&args[2], &args[3]),
                 Command::PRIVMSG(ref target,ref text) =>
                     self.privmsg(prefix, target, text),
                 _ => debug!("ignore: {:?}", msg)
@@ -72,35 +213,309 @@ impl IrcConn {
         }
     }
 
-    fn respond_to_name(&self, target: &str, nearby: Vec<Vec<&str>>) {
-        debug!("nearby words: {:?}", nearby);
-        let words = self.words.borrow_mut();
-        let result_words = words.new_complete_middle_out(nearby);
-        match result_words {
-            Ok(words) => {
-                let response = markov_words::join_phrase(vec![], words);
-                let res = self.client.send_privmsg(target, &response);
-                if let Err(x) = res {
-                    error!("Uhoh sending msg: {:?}",x);
+    // runs on the worker, so phrases learned meanwhile wait for the copy
+    fn schedule_backups(&self, backups: BackupConfig) {
+        let words = self.words.clone();
+        info!("Backing up to {} every {:?}", backups.dir.display(), backups.interval);
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + backups.interval;
+            let mut ticks = tokio::time::interval_at(start, backups.interval);
+            loop {
+                ticks.tick().await;
+                let config = backups.clone();
+                match words.call(move |words| words.backup_rotated(&config)).await {
+                    Ok(path) => info!("Backed up to {}", path.display()),
+                    Err(error::Error::WorkerGone) => break,
+                    Err(e) => error!("Backup failed: {}", e)
                 }
             }
-            Err(e) => error!("Uhoh: {:?}", e)
+        });
+    }
+
+    // None if a reply on target should be dropped, otherwise how long to hold it
+    fn limit(&self, target: &str, user: Option<&str>) -> Option<Duration> {
+        match self.limiter.check(target, user, Instant::now()) {
+            Decision::Send => Some(Duration::from_secs(0)),
+            Decision::Wait(wait) => {
+                info!("Queueing reply on {} for {:?}", target, wait);
+                Some(wait)
+            }
+            Decision::Drop(limit) => {
+                warn!("Dropping reply on {} to {}, over the {} limit", target, user.unwrap_or("nobody"), limit);
+                let words = self.words.clone();
+                let target = target.to_string();
+                tokio::spawn(async move {
+                    record_stat(&words, target, Counter::Limited).await;
+                });
+                None
+            }
+        }
+    }
+
+    fn respond_to_name(&self, target: &str, user: &str, phrase: Vec<String>) {
+        // leave our own nick out of the seed words
+        let nick = self.client.current_nickname().to_string();
+        let lower_nick = nick.to_lowercase();
+        let seeds: Vec<String> = phrase.iter()
+            .filter(|w| !w.to_lowercase().starts_with(&lower_nick))
+            .cloned()
+            .collect();
+        self.generate_reply(target, Some(user), seeds, move |words, seeds| {
+            let phrase: Vec<&str> = phrase.iter().map(AsRef::as_ref).collect();
+            let nearby = markov_words::find_nearby(&nick, &phrase);
+            debug!("nearby words: {:?}", nearby);
+            words.complete_reply(seeds, nearby)
+        });
+    }
+
+    // join in on a line that didn't mention us
+    fn interject(&self, target: &str, phrase: Vec<String>) {
+        self.generate_reply(target, None, phrase, |words, phrase| words.complete_interjection(phrase));
+    }
+
+    // complete from seeds on the worker, or now and then act instead, and
+    // send the result to target, for user if it's a reply, counting it as
+    // a reply or an error
+    fn generate_reply<F>(&self, target: &str, user: Option<&str>, seeds: Vec<String>, complete: F)
+    where F: FnOnce(&mut WordsDb, &[&str]) -> error::Result<Vec<String>> + Send + 'static {
+        let wait = match self.limit(target, user) {
+            Some(wait) => wait,
+            None => return
+        };
+        let act = random::<f64>() < self.action_chance;
+        let words = self.words.clone();
+        let sender = self.client.sender();
+        let nicks = self.nicks.clone();
+        let target = target.to_string();
+        let user = user.map(ToString::to_string);
+        tokio::spawn(async move {
+            let generated = words.call(move |words| {
+                let seeds: Vec<&str> = seeds.iter().map(AsRef::as_ref).collect();
+                if act {
+                    if let Some(action) = words.complete_action(&seeds)? {
+                        return Ok((true, action));
+                    }
+                }
+                complete(words, &seeds).map(|said| (false, said))
+            }).await;
+            let counter = match generated {
+                Ok((action, result_words)) => {
+                    let response = generated_body(&nicks, &target, user.as_deref(), action, result_words);
+                    info!("saying on {}: {}", target, response);
+                    delay_for(wait).await;
+                    match sender.send_privmsg(&target, &response) {
+                        Ok(()) => Counter::Replies,
+                        Err(e) => {
+                            error!("Uhoh sending msg: {:?}", e);
+                            Counter::Errors
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Couldn't generate a reply: {}", e);
+                    Counter::Errors
+                }
+            };
+            record_stat(&words, target, counter).await;
+        });
+    }
+
+    fn learn(&self, target: &str, phrase: Vec<String>, action: bool) {
+        let words = self.words.clone();
+        let target = target.to_string();
+        tokio::spawn(async move {
+            let channel = target.clone();
+            let learned = words.call(move |words| if action {
+                words.learn_action_from(&channel, &phrase)
+            } else {
+                words.learn_from(&channel, &phrase)
+            }).await;
+            if let Err(e) = learned {
+                error!("Error adding line: {}", e);
+                record_stat(&words, target, Counter::Errors).await;
+            }
+        });
+    }
+
+    fn run_command(&self, prefix: &Prefix, target: &str, line: Vec<String>) {
+        let (nick, mask) = match (prefix, hostmask::mask(prefix)) {
+            (Prefix::Nickname(nick, _, _), Some(mask)) => (nick.to_string(), mask),
+            _ => return
+        };
+        let level = self.commands.level(&mask);
+        // admins aren't limited
+        let wait = match level {
+            Level::Admin => Duration::from_secs(0),
+            Level::Anyone => match self.limit(target, Some(&nick)) {
+                Some(wait) => wait,
+                None => return
+            }
+        };
+        let caller = Caller { nick, mask, level };
+        let commands = self.commands.clone();
+        let words = self.words.clone();
+        let sender = self.client.sender();
+        let nicks = self.nicks.clone();
+        let quitting = self.quitting.clone();
+        let target = target.to_string();
+        tokio::spawn(async move {
+            let channel = target.clone();
+            let actions = words.call(move |words| commands.run(words, &caller, &channel, &line)).await;
+            let actions = match actions {
+                Ok(actions) => actions,
+                Err(e) => {
+                    error!("Error running command: {}", e);
+                    record_stat(&words, target.clone(), Counter::Errors).await;
+                    vec![Action::Say(format!("error: {}", e))]
+                }
+            };
+            delay_for(wait).await;
+            for action in actions {
+                let res = match action {
+                    // replies may quote generated text, admins' say is sent as is
+                    Action::Say(text) => sender.send_privmsg(&target, nicks.disrupt(&target, None, &text)),
+                    Action::SayTo(to, text) => sender.send_privmsg(&to, &text),
+                    Action::Join(channel) => sender.send_join(&channel),
+                    Action::Part(channel, message) => sender.send(Command::PART(channel, message)),
+                    Action::Nick(nick) => sender.send(Command::NICK(nick)),
+                    Action::Quit(message) => {
+                        quitting.store(true, Ordering::SeqCst);
+                        sender.send(Command::QUIT(message))
+                    }
+                };
+                if let Err(e) = res {
+                    error!("Uhoh sending command reply: {:?}", e);
+                }
+            }
+        });
+    }
+
+    // ignored senders can't teach, ask or command the bot, unless they're admins
+    fn ignored(&self, prefix: &Prefix) -> bool {
+        let (nick, mask) = match (prefix, hostmask::mask(prefix)) {
+            (Prefix::Nickname(nick, _, _), Some(mask)) => (nick, mask),
+            _ => return false
+        };
+        match self.ignores.matching(nick, &mask) {
+            Some(_) if self.commands.level(&mask) == Level::Admin => false,
+            Some(rule) => {
+                debug!("ignoring {} by rule {}", mask, rule);
+                true
+            }
+            None => false
         }
     }
 
     fn privmsg(&self, prefix: &Prefix, target: &str, text: &str) {
         info!("msg {:?} {} {}", prefix, target, text);
+        if self.ignored(prefix) {
+            return;
+        }
+        let nick = match prefix {
+            Prefix::Nickname(nick, _, _) => nick,
+            Prefix::ServerName(_) => return
+        };
+        let (text, action) = match ctcp::parse(text) {
+            None => (text, false),
+            Some(Ctcp::Action(text)) => (text, true),
+            Some(query) => {
+                self.answer_ctcp(nick, &query);
+                return;
+            }
+        };
+        // a query is sent to our nick, answer the sender instead
+        let in_query = !query::is_channel(target);
+        let target = query::reply_target(target, nick);
+        if !action {
+            if let Some(line) = self.commands.parse(text) {
+                // commands are neither learned nor answered
+                self.run_command(prefix, target, line);
+                return;
+            }
+        }
         let phrase = markov_words::tokenize_phrase(text);
-        let nearby = markov_words::find_nearby(self.client.current_nickname(), &phrase);
-        if nearby.is_empty() {
-            let owned_phrase: Vec<String> = phrase.iter().map(ToString::to_string).collect();
-            let words = self.words.borrow_mut();
-            if let Err(e) = words.add_phrase(&owned_phrase) {
-                error!("Error adding line: {}", e);
+        let mentioned = !markov_words::find_nearby(self.client.current_nickname(), &phrase).is_empty();
+        let owned_phrase: Vec<String> = phrase.iter().map(ToString::to_string).collect();
+        if in_query {
+            if self.query.reply && (mentioned || !self.query.mention) {
+                self.respond_to_name(target, nick, owned_phrase.clone());
+            }
+            if self.query.learn && !mentioned {
+                self.learn(target, owned_phrase, action);
             }
+        } else if mentioned {
+            self.respond_to_name(target, nick, owned_phrase);
         } else {
-            self.respond_to_name(target, nearby);
+            if self.interjector.should_interject(target, Instant::now(), random::<f64>()) {
+                self.interject(target, owned_phrase.clone());
+            }
+            self.learn(target, owned_phrase, action);
         }
     }
 
+    // VERSION, PING and TIME, answered with a NOTICE to nick
+    fn answer_ctcp(&self, nick: &str, query: &Ctcp) {
+        let now = chrono::Local::now().to_rfc2822();
+        let reply = match ctcp::reply(query, &now) {
+            Some(reply) => reply,
+            None => {
+                debug!("ignore CTCP {:?} from {}", query, nick);
+                return;
+            }
+        };
+        let wait = match self.limit(nick, Some(nick)) {
+            Some(wait) => wait,
+            None => return
+        };
+        let sender = self.client.sender();
+        let nick = nick.to_string();
+        tokio::spawn(async move {
+            delay_for(wait).await;
+            if let Err(e) = sender.send_notice(&nick, &reply) {
+                error!("Uhoh sending CTCP reply: {:?}", e);
+            }
+        });
+    }
+
+}
+
+// the privmsg body for a generated reply, sent as /me for an action,
+// and only addressed to user when it isn't one
+fn generated_body(nicks: &Nicks, target: &str, user: Option<&str>, action: bool, result_words: Vec<String>) -> String {
+    let text = markov_words::join_phrase(vec![], result_words);
+    if action {
+        return ctcp::action(&nicks.disrupt(target, user, &text));
+    }
+    match user {
+        Some(user) => nicks.reply(target, user, &text),
+        None => nicks.disrupt(target, None, &text)
+    }
+}
+
+async fn record_stat(words: &WordsWorker, channel: String, counter: Counter) {
+    let res = words.call(move |words| words.record_stat(&channel, counter, 1)).await;
+    if let Err(e) = res {
+        warn!("Couldn't record {} stat: {}", counter, e);
+    }
+}
+
+// waits out the backoff for the next attempt at connecting to server
+async fn wait_to_reconnect(reconnect: &ReconnectConfig, server: &str, attempt: &mut u32) {
+    let delay = reconnect.delay(*attempt, random::<f64>());
+    *attempt = attempt.saturating_add(1);
+    info!("Reconnecting to {} in {:?}", server, delay);
+    delay_for(delay).await;
+}
+
+// keeps trying to connect, backing off between attempts, so a network
+// that's down at startup is retried like one that drops later
+async fn connect_with_backoff(config: &Config, reconnect: &ReconnectConfig, attempt: &mut u32) -> Client {
+    let server = config.server.clone().unwrap_or_default();
+    loop {
+        match Client::from_config(config.clone()).await {
+            Ok(client) => return client,
+            Err(e) => error!("Couldn't connect to {}: {}", server, e)
+        }
+        wait_to_reconnect(reconnect, &server, attempt).await;
+    }
 }
@@ -4,6 +4,24 @@ extern crate rand;
 #[macro_use]
 extern crate log;
 
+pub mod backup;
+pub mod blocklist;
+pub mod check;
+pub mod commands;
+pub mod ctcp;
+pub mod error;
+pub mod filter;
+pub mod hostmask;
+pub mod ignore;
+pub mod interject;
 pub mod markov_words;
 pub mod ircconn;
 pub mod migration;
+pub mod networks;
+pub mod nicks;
+pub mod options;
+pub mod query;
+pub mod ratelimit;
+pub mod reconnect;
+pub mod stats;
+pub mod worker;
@@ -1,31 +1,286 @@
 use clap::{App, Arg, SubCommand, ArgMatches, AppSettings};
-use bazbot::markov_words::WordsDb;
-use bazbot::ircconn::IrcConn;
+use bazbot::markov_words::{self, Constraints, OpenMode, WordsConfig, WordsDb};
+use bazbot::check::Repair;
+use bazbot::stats::{Counter, COUNTERS};
+use bazbot::migration::MigrationState;
+use bazbot::ignore::Removed;
+use bazbot::networks;
+use bazbot::error::{Error, Result};
 use irc::client::data::config::Config;
-use std::env;
+use std::{env, process};
+use std::path::Path;
 
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 
-fn cmd_add_phrase(words: &WordsDb, matches: &ArgMatches) {
+fn cmd_add_phrase(words: &WordsDb, matches: &ArgMatches) -> Result<()> {
     let phrase = matches.values_of_lossy("words").unwrap_or_default();
-    words.add_phrase(&phrase).expect("failed");
+    words.add_phrase(&phrase)
 }
 
-fn cmd_read_phrases(words: &mut WordsDb, matches: &ArgMatches) {
+fn cmd_read_phrases(words: &mut WordsDb, matches: &ArgMatches) -> Result<()> {
     let files = matches.values_of_lossy("files").unwrap_or_default();
     for file in files {
-        words.read_file(&file).expect("couldn't read file");
+        words.read_file(&file)?;
     }
+    Ok(())
 }
 
-fn cmd_complete(words: &WordsDb, matches: &ArgMatches) {
-    let prefix = matches.values_of_lossy("prefix").unwrap_or_default();
-    words.print_complete(&prefix);
+fn cmd_complete(words: &WordsDb, matches: &ArgMatches) -> Result<()> {
+    let constraints = Constraints {
+        contains: matches.values_of_lossy("contains").unwrap_or_default(),
+        ends_with: matches.value_of_lossy("ends-with").map(|w| w.to_string())
+    };
+    if matches.is_present("explain") {
+        let prefix = matches.values_of_lossy("prefix").unwrap_or_default();
+        words.print_explain(&prefix);
+    } else if constraints.is_empty() {
+        let prefix = matches.values_of_lossy("prefix").unwrap_or_default();
+        words.print_complete(&prefix);
+    } else {
+        let phrase = words.complete_constrained(&constraints, markov_words::CONSTRAINT_ATTEMPTS)?;
+        println!("{}", markov_words::join_phrase(vec![], phrase));
+    }
+    Ok(())
+}
+
+fn cmd_migrate(words: &WordsDb, matches: &ArgMatches) -> Result<()> {
+    let statuses = words.migration_status()?;
+    match matches.value_of("action").unwrap_or("status") {
+        "up" => {
+            let pending = statuses.iter().filter(|s| s.state == MigrationState::Pending).count();
+            words.migrate()?;
+            println!("Applied {} migrations", pending);
+        }
+        "down" => {
+            match words.rollback_migration()? {
+                Some(m_id) => println!("Rolled back {}", m_id),
+                None => println!("Nothing to roll back")
+            }
+        }
+        "plan" => {
+            let pending: Vec<_> = statuses.iter()
+                .filter(|s| s.state == MigrationState::Pending)
+                .collect();
+            if pending.is_empty() {
+                println!("Nothing to migrate");
+            }
+            for status in pending {
+                println!("-- {}\n{}\n", status.migration.m_id, status.migration.m_sql.trim());
+            }
+        }
+        _ => {
+            for status in statuses {
+                let state = match status.state {
+                    MigrationState::Applied => "applied",
+                    MigrationState::Unverified => "applied (no checksum yet)",
+                    MigrationState::Changed => "CHANGED since applied",
+                    MigrationState::Pending => "pending"
+                };
+                println!("{:<28} {}", status.migration.m_id, state);
+            }
+            for m_id in words.unknown_migrations()? {
+                println!("{:<28} UNKNOWN, from a newer version?", m_id);
+            }
+            println!("Schema version {}", words.schema_version()?);
+        }
+    }
+    Ok(())
+}
+
+fn cmd_backup(words: &WordsDb, matches: &ArgMatches) -> Result<()> {
+    let dest = matches.value_of_os("dest").map(Path::new)
+        .ok_or_else(|| Error::Config("backup needs a destination file".to_string()))?;
+    words.backup(dest)?;
+    println!("Backed up to {}", dest.display());
+    Ok(())
+}
+
+fn cmd_block(words: &mut WordsDb, matches: &ArgMatches) -> Result<()> {
+    let entries = matches.values_of_lossy("entries").unwrap_or_default();
+    if entries.is_empty() {
+        for entry in words.blocklist() {
+            println!("{}", entry);
+        }
+        return Ok(());
+    }
+    for entry in entries {
+        if matches.is_present("remove") {
+            match words.unblock(&entry)? {
+                Removed::Removed => println!("Unblocked {}", entry),
+                Removed::FromConfig => println!("{} is in the config file, remove it there", entry),
+                Removed::NotFound => println!("{} isn't blocked", entry)
+            }
+        } else {
+            let added_by = env::var("USER").unwrap_or_else(|_| "command line".to_string());
+            println!("Blocked {}", words.block(&entry, &added_by)?);
+        }
+    }
+    Ok(())
 }
 
-async fn cmd_irc(words: WordsDb, config: Config) {
-    let mut irc = IrcConn::new_from_config(words, config);
-    irc.run().await
+// widest bar in stats --chart
+const CHART_WIDTH: i64 = 50;
+
+fn cmd_stats(words: &WordsDb, matches: &ArgMatches) -> Result<()> {
+    let days: u32 = match matches.value_of("days") {
+        Some(d) => d.parse().map_err(|e| Error::Config(format!("Couldn't parse days {}: {}", d, e)))?,
+        None => 14
+    };
+    let channel = matches.value_of("channel");
+    let history = words.stats_history(days, channel)?;
+    if let Some(counter) = matches.value_of("chart") {
+        let counter: Counter = counter.parse()?;
+        // one bar per day, including quiet ones
+        let today = chrono::Utc::today();
+        let totals: Vec<(String, i64)> = (0..i64::from(days)).rev()
+            .map(|ago| (today - chrono::Duration::days(ago)).format("%Y-%m-%d").to_string())
+            .map(|day| {
+                let total = history.iter().filter(|h| h.day == day).map(|h| h.get(counter)).sum();
+                (day, total)
+            })
+            .collect();
+        let most = totals.iter().map(|(_, total)| *total).max().unwrap_or(0).max(1);
+        println!("{} per day{}", counter, channel.map(|c| format!(" on {}", c)).unwrap_or_default());
+        for (day, total) in totals {
+            let bar = "#".repeat((total * CHART_WIDTH / most) as usize);
+            println!("{}  {:>7} {}", day, total, bar);
+        }
+        return Ok(());
+    }
+    if history.is_empty() {
+        println!("No activity recorded in the last {} days", days);
+        return Ok(());
+    }
+    print!("{:<10}  {:<20}", "day", "channel");
+    for counter in &COUNTERS {
+        print!(" {:>8}", counter.name());
+    }
+    println!();
+    for row in history {
+        print!("{:<10}  {:<20}", row.day, row.channel);
+        for counter in &COUNTERS {
+            print!(" {:>8}", row.get(*counter));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn cmd_check(words: &WordsDb, matches: &ArgMatches) -> Result<()> {
+    let problems = words.check(matches.is_present("repair"))?;
+    if problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    for problem in &problems {
+        let repair = match problem.repair {
+            Repair::NotAttempted => "",
+            Repair::Repaired => " (repaired)",
+            Repair::Unrepairable => " (can't repair)"
+        };
+        println!("{}: {}{}", problem.check, problem.detail, repair);
+    }
+    let remaining = problems.iter().filter(|p| p.repair != Repair::Repaired).count();
+    if remaining > 0 {
+        return Err(Error::Integrity(format!("{} problems remain", remaining)));
+    }
+    Ok(())
+}
+
+async fn cmd_irc(words: WordsDb, config: Config, cfg_file: &str) -> Result<()> {
+    networks::run(words, &config, Path::new(cfg_file)).await
+}
+
+// exit codes borrowed from sysexits.h
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Storage(_) => 65,         // EX_DATAERR
+        Error::Io(_) => 74,              // EX_IOERR
+        Error::LearningDisabled => 77,   // EX_NOPERM
+        Error::Config(_) => 78,          // EX_CONFIG
+        Error::Migration(_) => 72,       // EX_OSFILE
+        Error::Unsatisfiable(_) => 69,   // EX_UNAVAILABLE
+        Error::WorkerGone => 70,         // EX_SOFTWARE
+        Error::Irc(_) => 76,             // EX_PROTOCOL
+        Error::Integrity(_) => 66,       // EX_NOINPUT
+    }
+}
+
+fn report(err: &Error) {
+    match err {
+        Error::Storage(e) =>
+            eprintln!("Database error: {}\nIs this a valid bazbot database?", e),
+        Error::Io(e) =>
+            eprintln!("Couldn't read input: {}", e),
+        Error::LearningDisabled =>
+            eprintln!("Learning phrases is disabled by config (learn = false)"),
+        Error::Config(msg) =>
+            eprintln!("Configuration error: {}", msg),
+        Error::Migration(msg) =>
+            eprintln!("Database schema problem: {}", msg),
+        Error::Unsatisfiable(msg) =>
+            eprintln!("Couldn't generate a phrase: {}", msg),
+        Error::WorkerGone =>
+            eprintln!("Database worker stopped unexpectedly"),
+        Error::Irc(e) =>
+            eprintln!("IRC error: {}", e),
+        Error::Integrity(msg) =>
+            eprintln!("Database check failed: {}", msg),
+    }
+}
+
+async fn run(bazargs: &ArgMatches<'_>) -> Result<()> {
+    let cfg_file: String = bazargs.value_of_lossy("config")
+        .map(|arg| arg.to_string())
+        .or_else(|| env::var("BAZBOT_CONFIG").ok())
+        .unwrap_or_else(|| "bazbot.toml".to_string());
+    let cfg = Config::load(&cfg_file).map_err(|e| {
+        Error::Config(format!("Couldn't load config file {}: {}", &cfg_file, e))
+    })?;
+    if let ("migrate", Some(subm)) = bazargs.subcommand() {
+        // schema changes are allowed even when learn = false
+        let config = WordsConfig::from_irc_config(&cfg)?;
+        let words = match subm.value_of("action") {
+            Some("up") | Some("down") => WordsDb::open(config, OpenMode::ReadWrite)?,
+            _ => WordsDb::open_or_empty(config)?
+        };
+        return cmd_migrate(&words, subm);
+    }
+    if let ("backup", Some(subm)) = bazargs.subcommand() {
+        // works on any schema version, e.g. before migrate down
+        let words = WordsDb::open(WordsConfig::from_irc_config(&cfg)?, OpenMode::ReadOnly)?;
+        return cmd_backup(&words, subm);
+    }
+    if let ("check", Some(subm)) = bazargs.subcommand() {
+        // runs before verify_migrations so it can report schema problems
+        let mode = if subm.is_present("repair") { OpenMode::ReadWrite } else { OpenMode::ReadOnly };
+        let words = WordsDb::open(WordsConfig::from_irc_config(&cfg)?, mode)?;
+        return cmd_check(&words, subm);
+    }
+    let mode = match bazargs.subcommand_name() {
+        // never write, so they can run next to the bot
+        Some("summary") | Some("complete") | Some("stats") => OpenMode::ReadOnly,
+        _ => OpenMode::ReadWrite
+    };
+    let mut words = WordsDb::from_config(&cfg, mode)?;
+    words.verify_migrations()?;
+    words.load_blocklist()?;
+
+    match bazargs.subcommand() {
+        ("summary", Some(_)) => words.summary(),
+        ("add", Some(subm)) => cmd_add_phrase(&words, subm)?,
+        ("read", Some(subm)) => cmd_read_phrases(&mut words, subm)?,
+        ("complete", Some(subm)) => cmd_complete(&words, subm)?,
+        ("stats", Some(subm)) => cmd_stats(&words, subm)?,
+        ("block", Some(subm)) => cmd_block(&mut words, subm)?,
+        ("irc", Some(_)) => cmd_irc(words, cfg, &cfg_file).await?,
+        _ => {
+            // Can't use App print_help because we
+            // used get_matches instead.
+            println!("Unknown subcommand (try help)");
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -48,27 +303,131 @@ async fn main(){
         .setting(AppSettings::SubcommandRequired)
         .subcommand(SubCommand::with_name("summary")
             .about("Summarize database"))
+        .subcommand(SubCommand::with_name("stats")
+            .about("List activity per day and channel, or chart one counter")
+            .arg(Arg::with_name("days")
+                .long("days")
+                .takes_value(true)
+                .value_name("N")
+                .help("Days of history to show, including today (default 14)"))
+            .arg(Arg::with_name("channel")
+                .long("channel")
+                .takes_value(true)
+                .value_name("CHANNEL")
+                .help("Only show activity on CHANNEL"))
+            .arg(Arg::with_name("chart")
+                .long("chart")
+                .takes_value(true)
+                .possible_values(&["lines", "words", "trigrams", "replies", "errors", "limited", "reconnects"])
+                .help("Chart one counter per day, summed over channels")))
         .subcommand(SubCommand::with_name("complete")
-            .about("Run a markov chain matching args around _")
-            .arg(Arg::with_name("prefix").multiple(true)))
+            .about("Run a markov chain matching args around _, or fill in each _ of a template")
+            .arg(Arg::with_name("prefix").multiple(true))
+            .arg(Arg::with_name("contains")
+                .long("contains")
+                .takes_value(true).multiple(true).number_of_values(1)
+                .value_name("WORD")
+                .help("Generate a phrase containing WORD (may be repeated)"))
+            .arg(Arg::with_name("explain")
+                .long("explain")
+                .help("Complete forward from the args, showing the context and backoff for each word"))
+            .arg(Arg::with_name("ends-with")
+                .long("ends-with")
+                .takes_value(true)
+                .value_name("WORD")
+                .help("Generate a phrase ending with WORD")))
         .subcommand(SubCommand::with_name("add")
             .about("Add a phrase to the markov words database")
             .arg(Arg::with_name("words").multiple(true)))
         .subcommand(SubCommand::with_name("read")
             .about("Read text file with one phrase per line into markov database")
             .arg(Arg::with_name("files").multiple(true).value_name("file.txt")))
+        .subcommand(SubCommand::with_name("block")
+            .about("List, add or remove words the bot never says")
+            .arg(Arg::with_name("entries")
+                .multiple(true)
+                .value_name("WORD|/REGEX/"))
+            .arg(Arg::with_name("remove")
+                .long("remove")
+                .help("Remove the entries instead of adding them")))
         .subcommand(SubCommand::with_name("irc")
             .about("Interact on irc channels"))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Show or apply database migrations")
+            .arg(Arg::with_name("action")
+                .possible_values(&["status", "plan", "up", "down"])
+                .default_value("status")
+                .help("status lists migrations, plan shows pending sql, up applies it, \
+                       down rolls back the last migration; every migration but the first can be rolled back")))
+        .subcommand(SubCommand::with_name("backup")
+            .about("Copy the database to a file, safe while the bot is running")
+            .arg(Arg::with_name("dest")
+                .required(true)
+                .value_name("dest.db")))
+        .subcommand(SubCommand::with_name("check")
+            .about("Check the database for broken invariants")
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .help("Fix problems that can be repaired safely, in one transaction")))
         .after_help("
 Files:
     - bazbot.toml or
     - bazbot.json
         A valid config file is required to connect to irc.
+        [[networks]] tables connect to more networks, each with
+        its own server, nickname, channels and options.
         See irc library documentation for more information:
            https://github.com/aatxe/irc
         The following options are supported:
          - words - sqlite database to store phrases
          - learn - learn new phrases from irc
+         - seed - how replies are seeded: nearby (words around the
+                  bot's nick, default) or rare (the rarest known word)
+         - backoff - context words chains may drop when a word pair
+                     was never seen: 0, 1 (default) or 2
+         - fuzzy - max edit distance when matching unknown words in
+                   a message to known spellings (default 2)
+         - backup_dir - directory for scheduled backups while on irc
+         - backup_hours - hours between backups (default 24)
+         - backup_keep - backups to keep in backup_dir (default 7)
+         - command_prefix - starts commands on irc (default !baz)
+         - admins - nick!user@host globs allowed to run admin commands
+         - interject - chance (0 to 1) of joining in on a line that
+                       doesn't mention the bot (default 0, never)
+         - interject_channels - per channel chances, #chan=0.05 ...
+         - interject_cooldown - least seconds between interjections
+                                on a channel (default 300)
+         - action_chance - chance (0 to 1) a reply or interjection
+                           is a /me action instead (default 0.1)
+         - rate_global, rate_channel, rate_user - reply limits as
+                count/seconds (defaults 20/60, 6/60 and 3/60)
+         - rate_overflow - drop (default) or queue replies over a limit
+         - ignore - comma separated senders not to learn from or
+                    answer: nick globs, nick!user@host globs or /regex/
+         - query_learn - learn from private messages (default false)
+         - query_reply - answer private messages (default true)
+         - query_mention - only answer private messages that
+                           mention the bot's nick (default false)
+         - filter_min_tokens, filter_max_tokens - words a line needs
+                to be learned, urls aside (defaults 2 and 50)
+         - filter_prefixes - lines starting with these aren't
+                             learned (default !, other bots' commands)
+         - filter_urls - leave urls out of learned lines (default true)
+         - filter_code - skip code and base64 or hex (default true)
+         - filter_deny - comma separated regexes of lines to skip
+         - filter_non_text - skip lines with more than this fraction
+                             of non letters (default 0.5)
+         - block - comma separated words, or /regex/, never said
+         - disrupt_nicks - keep the bot's messages from highlighting
+                channel members: zero-width (default), placeholder
+                or off
+         - nick_placeholder - replaces nicks with disrupt_nicks =
+                              placeholder (default someone)
+         - address_sender - start replies with the nick of whoever
+                            the reply is for (default false)
+         - reconnect_min, reconnect_max - seconds to wait before
+                connecting again after losing the server, doubling
+                from min up to max (defaults 5 and 600)
     - bazbot.db
         default sqlite file storing phrases
 
@@ -79,25 +438,10 @@ Environment
     BAZBOT_WORDS    - default sqlite database location")
         .get_matches();
 
-    let cfg_file: String = bazargs.value_of_lossy("config")
-        .map(|arg| arg.to_string())
-        .or_else(|| env::var("BAZBOT_CONFIG").ok())
-        .unwrap_or_else(|| "bazbot.toml".to_string());
-    let cfg = Config::load(&cfg_file).expect(&format!("Couldn't load config file {}", &cfg_file));
-    let mut words = WordsDb::from_config(&cfg);
-    words.migrate().expect("Database migration failed");
-
-    match bazargs.subcommand() {
-        ("summary", Some(_)) => words.summary(),
-        ("add", Some(subm)) => cmd_add_phrase(&words, subm),
-        ("read", Some(subm)) => cmd_read_phrases(&mut words, subm),
-        ("complete", Some(subm)) => cmd_complete(&words, subm),
-        ("irc", Some(_)) => cmd_irc(words, cfg).await,
-        _ => {
-            // Can't use App print_help because we
-            // used get_matches instead.
-            println!("Unknown subcommand (try help)");
-        }
+    if let Err(e) = run(&bazargs).await {
+        log::debug!("Exiting on error: {:?}", e);
+        report(&e);
+        process::exit(exit_code(&e));
     }
 }
 
@@ -1,11 +1,25 @@
 extern crate irc;
 
+use crate::backup::{self, BackupConfig};
+use crate::blocklist::Blocklist;
+use crate::check;
+use crate::filter::LearnFilter;
+use crate::ignore::Removed;
 use crate::migration;
-use std::{env,fs};
+use crate::options::parse_option;
+use crate::stats::{self, Counter, DayStats};
+use crate::error::{Error, Result};
+use std::{env,fmt,fs};
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
 use std::io::{BufRead,BufReader};
-use rusqlite::{Result, Connection,Error};
+use rusqlite::{Connection, OpenFlags};
+use std::time::Duration;
 use rusqlite::types::ToSql;
 use rand::random;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 use self::irc::client::data::config::Config;
 
 enum WordField {
@@ -56,8 +70,17 @@ pub struct ChainIter<'a> {
     // - the first field after the value is the output field
     filter_fields: Vec<&'a str>,  // Should always be 3
     filter_values: Vec<i64>,      // 0-2 values
+    // phrases or action_phrases
+    table: &'static str,
     // infinite loop guard in case of bad data
-    count: i64
+    count: i64,
+    // set after an error so the chain ends instead of retrying
+    failed: bool,
+    // how many of the oldest filter values may be dropped
+    // when the full context has no continuation
+    backoff: usize,
+    // how many were dropped to pick the last word
+    last_backoff: usize
 }
 
 impl<'a> ChainIter<'a> {
@@ -68,21 +91,57 @@ impl<'a> ChainIter<'a> {
         }
         self.filter_values.push(n);
     }
+
+    // Katz-style backoff: drop the oldest `depth` filter values, keeping
+    // the rest on their own fields, so (word1=A, word2=B) -> word3
+    // backs off to (word2=B) -> word3
+    fn backoff_filter(&self, depth: usize) -> Vec<NamedParam<'a>> {
+        self.filter_fields.iter()
+            .zip(self.filter_values.iter())
+            .skip(depth)
+            .map(|(f,v)| NamedParam::new(f,Box::new(*v)))
+            .collect()
+    }
+
+    // values the last word was chosen from, after backoff
+    pub fn context(&self) -> &[i64] {
+        &self.filter_values
+    }
+
+    // how many context words were dropped to choose the last word
+    pub fn last_backoff(&self) -> usize {
+        self.last_backoff
+    }
 }
 
 impl<'a> Iterator for ChainIter<'a> {
-    type Item = i64;
-    // TODO: Should this be a Option<Result<i64>> ?
-    fn next(&mut self) -> Option<i64> {
-        let filter: Vec<NamedParam> = self.filter_fields.iter()
-                                       .zip(self.filter_values.iter())
-                                       .map(|(f,v)| NamedParam::new(*f,Box::new(*v)))
-                                       .collect();
-        let res = {
-            let select_field = self.filter_fields
-                .get(filter.len()).expect("missing filter field");
-            self.words.complete_any(select_field, &filter)
+    type Item = Result<i64>;
+    fn next(&mut self) -> Option<Result<i64>> {
+        if self.failed {
+            return None;
+        }
+        let select_field = *self.filter_fields
+            .get(self.filter_values.len()).expect("missing filter field");
+        // a trailing 0 is the end of a phrase, never back off from that,
+        // and below MAX_BACKOFF keep at least MAX_BACKOFF - backoff words
+        // of context, so 1 backs off to the last word alone
+        let max_depth = match self.filter_values.last() {
+            Some(0) | None => 0,
+            Some(_) => self.filter_values.len().saturating_sub(MAX_BACKOFF - self.backoff)
         };
+        let mut res = Ok(None);
+        for depth in 0..=max_depth {
+            let filter = self.backoff_filter(depth);
+            res = self.words.complete_any(self.table, select_field, &filter);
+            if let Ok(None) = res {
+                continue;
+            }
+            if depth > 0 {
+                debug!("backoff {} from {:?}", depth, self.filter_values);
+            }
+            self.last_backoff = depth;
+            break;
+        }
         self.count += 1;
         if self.count > 200 {
             error!("Aborting long phrase, possible loop, current filter values: {:?}", self.filter_values);
@@ -91,17 +150,128 @@ impl<'a> Iterator for ChainIter<'a> {
         match res {
             Ok(Some(n)) => {
                 self.push(n);
-                Some(n)
+                Some(Ok(n))
             },
             Ok(None) => None,
             Err(e) => {
-                warn!("Ending early due to {:?}", e);
-                None
+                self.failed = true;
+                Some(Err(e))
             }
         }
     }
 }
 
+// wait this long for another connection to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a connection may write to the database
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenMode {
+    ReadWrite,
+    // for commands that never write, and brains with learn = false
+    ReadOnly,
+}
+
+// chains back off from trigram to bigram context by default
+const DEFAULT_BACKOFF: usize = 1;
+const MAX_BACKOFF: usize = 2;
+
+// unknown seed words match known spellings this many edits away
+const DEFAULT_FUZZY: usize = 2;
+
+/// One word of a completion and how it was chosen
+#[derive(Debug)]
+pub struct Explained {
+    pub word: String,
+    // context words used to choose it
+    pub context: Vec<String>,
+    // how many older context words were dropped
+    pub backoff: usize,
+}
+
+// what adding a phrase added, for the stats
+#[derive(Debug, Default)]
+struct Learned {
+    new_words: i64,
+    new_trigrams: i64,
+}
+
+// resampling budget for constrained completion
+pub const CONSTRAINT_ATTEMPTS: usize = 100;
+
+/// Requirements for a generated phrase
+#[derive(Debug, Default, Clone)]
+pub struct Constraints {
+    // every word must appear somewhere in the phrase
+    pub contains: Vec<String>,
+    // the last word of the phrase
+    pub ends_with: Option<String>,
+}
+
+impl Constraints {
+    pub fn is_empty(&self) -> bool {
+        self.contains.is_empty() && self.ends_with.is_none()
+    }
+}
+
+impl fmt::Display for Constraints {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if !self.contains.is_empty() {
+            parts.push(format!("containing {}", self.contains.join(", ")));
+        }
+        if let Some(end) = &self.ends_with {
+            parts.push(format!("ending with {}", end));
+        }
+        write!(f, "{}", parts.join(" and "))
+    }
+}
+
+// limits for bridging a blank between two fixed parts of a template
+const BRIDGE_MAX_SPAN: usize = 8;
+const BRIDGE_ATTEMPTS: usize = 20;
+
+// one piece of a template: a word to keep, or a blank to fill
+#[derive(Debug, PartialEq)]
+enum TemplatePart<'a> {
+    Fixed(&'a str),
+    Blank,
+}
+
+// split template words into fixed words and blanks,
+// consecutive blanks are one span
+fn parse_template<'a>(template: &[&'a str]) -> Vec<TemplatePart<'a>> {
+    let mut parts: Vec<TemplatePart> = Vec::with_capacity(template.len());
+    for word in template {
+        if *word == "_" {
+            if parts.last() != Some(&TemplatePart::Blank) {
+                parts.push(TemplatePart::Blank);
+            }
+        } else {
+            parts.push(TemplatePart::Fixed(word));
+        }
+    }
+    parts
+}
+
+// Phrases are learned and chained separately for each kind, so actions
+// only ever grow into actions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhraseKind {
+    Said,
+    // /me actions
+    Action,
+}
+
+impl PhraseKind {
+    fn table(self) -> &'static str {
+        match self {
+            PhraseKind::Said => "phrases",
+            PhraseKind::Action => "action_phrases"
+        }
+    }
+}
+
 // join two vector phrases with spaces
 pub fn join_phrase(phrase1: Vec<String>, phrase2: Vec<String>) -> String {
     phrase1.into_iter()
@@ -113,11 +283,11 @@ pub fn join_phrase(phrase1: Vec<String>, phrase2: Vec<String>) -> String {
 }
 
 // demote error result to Ok(None) and combine results
-fn no_rows_as_none<T>(result: Result<Result<Option<T>>>) -> Result<Option<T>> {
+fn no_rows_as_none<T>(result: rusqlite::Result<rusqlite::Result<Option<T>>>) -> Result<Option<T>> {
     match result {
-        Err(Error::QueryReturnedNoRows) => Ok(None),
-        Err(x) | Ok(Err(x)) => Err(x),
-        Ok(ok) => ok
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(x) | Ok(Err(x)) => Err(Error::from(x)),
+        Ok(ok) => Ok(ok?)
     }
 }
 
@@ -137,6 +307,68 @@ fn last_n<T: Copy>(vec: &[T], limit: usize) -> Vec<T> {
         .into_iter().rev().collect::<Vec<T>>()
 }
 
+// last two ids to continue a chain from, a trailing 0 restarts the phrase
+fn chain_context(ids: &[i64]) -> Vec<i64> {
+    match ids.last() {
+        Some(0) | None => vec![0],
+        Some(_) => last_n(ids, 2)
+    }
+}
+
+// lowercase and trim punctuation from both ends: "Rust?" -> "rust"
+pub fn fold_spelling(spelling: &str) -> String {
+    let lower = spelling.to_lowercase();
+    let trimmed = lower.trim_matches(|c: char| !c.is_alphanumeric());
+    if trimmed.is_empty() {
+        lower
+    } else {
+        trimmed.to_string()
+    }
+}
+
+// strip a few common english suffixes from a folded spelling
+pub fn stem_spelling(folded: &str) -> String {
+    let len = folded.chars().count();
+    let strip = |suffix: &str, min_len: usize, replace: &str| -> Option<String> {
+        if len >= min_len && folded.ends_with(suffix) {
+            Some(format!("{}{}", &folded[..folded.len() - suffix.len()], replace))
+        } else {
+            None
+        }
+    };
+    strip("ies", 5, "y")
+        .or_else(|| strip("ing", 6, ""))
+        .or_else(|| strip("ed", 5, ""))
+        .or_else(|| strip("es", 5, ""))
+        .or_else(|| if folded.ends_with("ss") { None } else { strip("s", 4, "") })
+        .unwrap_or_else(|| folded.to_string())
+}
+
+// optimal string alignment distance: levenshtein plus adjacent transpositions
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
 // split on white space and add begin/end sentinels
 pub fn tokenize_phrase(phrase: &str) -> Vec<&str> {
     // vec![""].into_iter().chain(phrase.split_whitespace()).chain(vec![""]).collect::<Vec<&str>>()
@@ -150,99 +382,196 @@ pub fn tokenize_phrase(phrase: &str) -> Vec<&str> {
 // each vec is a vec of 2 words or ""
 pub fn find_nearby<'a>(needle: &str, haystack: &[&'a str]) -> Vec<Vec<&'a str>> {
     let lower_needle = needle.to_lowercase();
+    match haystack.iter().position(|s| s.to_lowercase().starts_with(&lower_needle)) {
+        Some(pos) => nearby_at(haystack, pos),
+        None => vec![]
+    }
+}
+
+// the pairs of words either side of haystack[pos]
+pub fn nearby_at<'a>(haystack: &[&'a str], pos: usize) -> Vec<Vec<&'a str>> {
     // add begin/end framing
     let framed: Vec<&str> = vec![""].into_iter()
             .chain(haystack.iter().cloned())
             .chain(vec![""].into_iter())
             .collect();
+    let pos = pos + 1;
+    let mut found = vec![];
+    if pos > 1 {
+        found.push( vec![ framed[pos-2], framed[pos-1] ] );
+    }
 
-    if let Some(pos) = framed.iter()
-        .position(|s| s.to_lowercase().starts_with(&lower_needle)) {
-        let mut found = vec![];
-        if pos > 1 {
-            found.push( vec![ framed[pos-2], framed[pos-1] ] );
-        }
+    if pos < framed.len() - 2 {
+        found.push( vec![ framed[pos+1], framed[pos+2] ] );
+    }
 
-        if pos < framed.len() - 2 {
-            found.push( vec![ framed[pos+1], framed[pos+2] ] );
-        }
+    if found.is_empty() {
+        found.push( vec![""] );
+    }
+    found
+}
 
-        if found.is_empty() {
-            found.push( vec![""] );
-        }
-        found
+// How replies pick the words they are built around
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedMode {
+    // the two words on either side of the bot's nick
+    Nearby,
+    // the rarest word in the message the corpus knows about
+    Rare,
+}
 
-    } else {
-        vec![]
+impl FromStr for SeedMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<SeedMode> {
+        match s {
+            "nearby" => Ok(SeedMode::Nearby),
+            "rare" => Ok(SeedMode::Rare),
+            _ => Err(Error::Config(format!("Unknown seed mode {} (try nearby or rare)", s)))
+        }
     }
 }
 
+// options read into WordsConfig, which belong to the database rather
+// than to a connection using it
+pub fn is_words_option(option: &str) -> bool {
+    ["words", "learn", "seed", "backoff", "fuzzy", "block"].contains(&option) || option.starts_with("filter_")
+}
+
 #[derive(Debug)]
 pub struct WordsConfig {
     db_url: String,
     learn_new_phrases: bool,
+    seed_mode: SeedMode,
+    // 0: trigrams only, 1: back off to bigrams, 2: and then to single words
+    backoff: usize,
+    // max edit distance when looking up unknown seed words
+    fuzzy: usize,
+    // lines from irc and read files that aren't learned
+    filter: LearnFilter,
+    // words never chosen for a reply, from the config and the database
+    blocklist: Blocklist,
 }
 impl WordsConfig {
+    pub fn db_url(&self) -> &str {
+        &self.db_url
+    }
 
     // Optional, because irc
-    pub fn from_irc_config(config: &Config) -> WordsConfig {
-        let mut db_url: String = "bazbot.db".to_string();
-        let mut learn_new_phrases = true;
-        let opt_words = config.options
+    pub fn from_irc_config(config: &Config) -> Result<WordsConfig> {
+        let db_url = config.options
             .get("words").cloned()
-            .or_else(|| -> Option<String> { env::var("BAZBOT_WORDS").ok() } );
-        db_url = opt_words.unwrap_or(db_url);
-        learn_new_phrases = config.options
-            .get("learn")
-            .map(|l: &String| -> bool {
-                l.parse().unwrap_or_else(|e| {
-                    error!("Couldn't parse learn as bool {}: {}", l, e);
-                    learn_new_phrases
-                })
-            })
-            .unwrap_or(learn_new_phrases);
-        WordsConfig {
+            .or_else(|| -> Option<String> { env::var("BAZBOT_WORDS").ok() } )
+            .unwrap_or_else(|| "bazbot.db".to_string());
+        let learn_new_phrases = parse_option(config, "learn", "bool", true)?;
+        let seed_mode = match config.options.get("seed") {
+            Some(mode) => mode.parse()?,
+            None => SeedMode::Nearby
+        };
+        let backoff = match config.options.get("backoff") {
+            Some(b) => match b.parse() {
+                Ok(depth) if depth <= MAX_BACKOFF => depth,
+                _ => return Err(Error::Config(
+                    format!("backoff should be 0 to {}, not {}", MAX_BACKOFF, b)))
+            },
+            None => DEFAULT_BACKOFF
+        };
+        let fuzzy = parse_option(config, "fuzzy", "a distance", DEFAULT_FUZZY)?;
+        Ok(WordsConfig {
             db_url,
-            learn_new_phrases
-        }
+            learn_new_phrases,
+            seed_mode,
+            backoff,
+            fuzzy,
+            filter: LearnFilter::from_irc_config(config)?,
+            blocklist: Blocklist::from_irc_config(config)?
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct WordsDb {
     db: Connection,
-    config: Box<WordsConfig>
+    config: Box<WordsConfig>,
+    mode: OpenMode,
+    // ids of the words the blocklist matches, kept up to date as entries
+    // change and words are learned so choosing words needs no lookups
+    blocked: RefCell<HashSet<i64>>
+}
+
+// Open with a busy timeout, and in WAL mode when writing so that
+// readers like summary don't get "database is locked" from a running bot
+fn open_connection(db_url: &str, mode: OpenMode) -> Result<Connection> {
+    let db = match mode {
+        OpenMode::ReadWrite => Connection::open(db_url)?,
+        OpenMode::ReadOnly => Connection::open_with_flags(db_url,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?
+    };
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    if mode == OpenMode::ReadWrite {
+        // journal mode is stored in the file, so readers get it too
+        let journal: String = db.query_row("PRAGMA journal_mode=WAL", &[], |row| row.get(0))?;
+        debug!("journal mode: {}", journal);
+    }
+    Ok(db)
 }
 
 
 impl WordsDb {
-    pub fn new(db_url: String) -> WordsDb {
-        debug!("Open db {}", db_url);
-        let db = Connection::open(&db_url)
-                .expect("Could not open database");
+    pub fn new(db_url: String) -> Result<WordsDb> {
         let config = WordsConfig {
-            db_url: db_url,
-            learn_new_phrases: true
+            db_url,
+            learn_new_phrases: true,
+            seed_mode: SeedMode::Nearby,
+            backoff: DEFAULT_BACKOFF,
+            fuzzy: DEFAULT_FUZZY,
+            filter: LearnFilter::default(),
+            blocklist: Blocklist::default()
         };
-        WordsDb {
-            db,
-            config: Box::new(config)
-        }
+        WordsDb::open(config, OpenMode::ReadWrite)
     }
-    pub fn from_config(irc_config: &Config) -> WordsDb {
-        let config = WordsConfig::from_irc_config(irc_config);
-        let db = Connection::open(&config.db_url)
-                .expect("Could not open database");
+
+    // learn = false always opens read only
+    pub fn from_config(irc_config: &Config, mode: OpenMode) -> Result<WordsDb> {
+        let config = WordsConfig::from_irc_config(irc_config)?;
         info!("Loading config: {:?}", config);
-        WordsDb {
+        let mode = if config.learn_new_phrases { mode } else { OpenMode::ReadOnly };
+        WordsDb::open(config, mode)
+    }
+
+    pub fn open(config: WordsConfig, mode: OpenMode) -> Result<WordsDb> {
+        debug!("Open db {} {:?}", config.db_url, mode);
+        let db = open_connection(&config.db_url, mode)?;
+        Ok(WordsDb {
             db,
-            config: Box::new(config)
+            config: Box::new(config),
+            mode,
+            blocked: RefCell::new(HashSet::new())
+        })
+    }
+
+    /// Open read only, or as an empty database in memory when the file
+    /// doesn't exist yet, so migrate status and plan list every migration
+    /// as pending without creating the file
+    pub fn open_or_empty(config: WordsConfig) -> Result<WordsDb> {
+        if Path::new(&config.db_url).exists() {
+            return WordsDb::open(config, OpenMode::ReadOnly);
         }
+        debug!("{} doesn't exist yet, reading an empty database", config.db_url);
+        Ok(WordsDb {
+            db: Connection::open_in_memory()?,
+            config: Box::new(config),
+            mode: OpenMode::ReadOnly,
+            blocked: RefCell::new(HashSet::new())
+        })
+    }
+
+    pub fn mode(&self) -> OpenMode {
+        self.mode
     }
 
     pub fn summary(&self) {
         println!("Summary of {:?}", self);
-        let words: Result<i64> = self.db.query_row(
+        let words: rusqlite::Result<i64> = self.db.query_row(
             "select count(*) from words", &[], |row| row.get(0));
         match words.as_ref() {
             Ok(words) => println!("Words: {}", words),
@@ -260,14 +589,174 @@ impl WordsDb {
     }
 
     pub fn migrate(&self) -> Result<()> {
-        migration::migrate(&self.db)
+        if self.mode == OpenMode::ReadOnly {
+            debug!("Read only, skipping migrations");
+            return Ok(());
+        }
+        migration::migrate(&self.db)?;
+        self.index_word_forms()
+    }
+
+    // fail unless the schema is fully migrated, never writes
+    pub fn verify_migrations(&self) -> Result<()> {
+        migration::verify(&self.db)
+    }
+
+    pub fn migration_status(&self) -> Result<Vec<migration::MigrationStatus>> {
+        migration::status(&self.db)
+    }
+
+    // ids of applied migrations this binary doesn't know
+    pub fn unknown_migrations(&self) -> Result<Vec<String>> {
+        migration::unknown(&self.db)
+    }
+
+    pub fn schema_version(&self) -> Result<i64> {
+        migration::schema_version(&self.db)
+    }
+
+    // roll back the last migration, returning its id
+    pub fn rollback_migration(&self) -> Result<Option<String>> {
+        migration::rollback(&self.db)
+    }
+
+    // consistent copy of the database, safe while the bot is writing
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        backup::backup(&self.db, dest)
+    }
+
+    // scheduled backup, returning the new backup's path
+    pub fn backup_rotated(&self, config: &BackupConfig) -> Result<PathBuf> {
+        backup::rotate(&self.db, &self.config.db_url, config)
+    }
+
+    // count activity on channel, a no-op when read only
+    pub fn record_stat(&self, channel: &str, counter: Counter, n: i64) -> Result<()> {
+        if self.mode == OpenMode::ReadOnly {
+            return Ok(());
+        }
+        stats::record(&self.db, channel, counter, n)
     }
 
-    fn complete_any(&self, select_field: &str,  filter: &[NamedParam]) -> Result<Option<i64>> {
-        match self.get_freq_where(filter) {
+    pub fn stats_history(&self, days: u32, channel: Option<&str>) -> Result<Vec<DayStats>> {
+        stats::history(&self.db, days, channel)
+    }
+
+    // look for broken invariants, fixing what's safe when repair is set
+    pub fn check(&self, repair: bool) -> Result<Vec<check::Problem>> {
+        check::check(&self.db, repair)
+    }
+
+    // fill word_forms for words learned before it existed
+    fn index_word_forms(&self) -> Result<()> {
+        let mut stmt = self.db.prepare(
+            "select word_id, spelling from words
+             where word_id<>0 and word_id not in (select word_id from word_forms)")?;
+        let missing = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        if !missing.is_empty() {
+            info!("Indexing {} word forms", missing.len());
+            migration::in_transaction(&self.db, || {
+                for (word_id, spelling) in missing {
+                    Self::add_word_forms_db(&self.db, word_id, &spelling)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn add_word_forms_db(db: &Connection, word_id: i64, spelling: &str) -> Result<()> {
+        let folded = fold_spelling(spelling);
+        let stem = stem_spelling(&folded);
+        let len = folded.chars().count() as i64;
+        db.execute(
+            "insert into word_forms (word_id, folded, stem, len) values (?,?,?,?)",
+            &[&word_id, &folded, &stem, &len])?;
+        Ok(())
+    }
+
+    // the most used of the words matching a where clause on word_forms
+    fn most_used_form(&self, sql_where: &str, params: &[&dyn ToSql]) -> Result<Option<i64>> {
+        let sql = format!(
+            "select f.word_id from word_forms f where {}
+             order by (select coalesce(sum(freq),0) from phrases where word2=f.word_id) desc
+             limit 1", sql_where);
+        no_rows_as_none(self.db.query_row(&sql, params, |row| row.get_checked(0)))
+    }
+
+    /// Find the closest known spelling of an unknown word
+    ///
+    /// Tries, in order: the same word ignoring case and punctuation,
+    /// the same stem, a known word the spelling is a prefix of, and
+    /// finally the nearest spelling within the configured edit distance.
+    pub fn fuzzy_word_id(&self, spelling: &str) -> Result<Option<i64>> {
+        let folded = fold_spelling(spelling);
+        if folded.is_empty() {
+            return Ok(None);
+        }
+        if let Some(word_id) = self.most_used_form("f.folded=?", &[&folded])? {
+            return Ok(Some(word_id));
+        }
+        let stem = stem_spelling(&folded);
+        if let Some(word_id) = self.most_used_form("f.stem=?", &[&stem])? {
+            return Ok(Some(word_id));
+        }
+        let len = folded.chars().count();
+        // short prefixes match too much to be useful
+        if len >= 3 {
+            let mut upper = folded.clone();
+            upper.push(std::char::MAX);
+            let found = no_rows_as_none(self.db.query_row(
+                "select word_id from word_forms where folded>? and folded<? order by len limit 1",
+                &[&folded, &upper], |row| row.get_checked(0)))?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        // allow fewer edits for short words, "a" shouldn't become "i"
+        let max_distance = self.config.fuzzy.min(len / 3);
+        if max_distance == 0 {
+            return Ok(None);
+        }
+        let mut best: Option<(usize, i64)> = None;
+        let mut stmt = self.db.prepare("select word_id, folded from word_forms where len=?")?;
+        for candidate_len in len.saturating_sub(max_distance)..=len + max_distance {
+            let candidates = stmt.query_map(&[&(candidate_len as i64)], |row| (row.get(0), row.get(1)))?;
+            for candidate in candidates {
+                let (word_id, candidate): (i64, String) = candidate?;
+                let distance = edit_distance(&folded, &candidate);
+                let closer = match best {
+                    Some((best_distance, _)) => distance < best_distance,
+                    None => true
+                };
+                if distance <= max_distance && closer {
+                    best = Some((distance, word_id));
+                }
+            }
+        }
+        Ok(best.map(|(_, word_id)| word_id))
+    }
+
+    // exact spelling, or the closest known spelling
+    fn lookup_word_id(&self, spelling: &str) -> Result<Option<i64>> {
+        match self.get_word_id(spelling)? {
+            Some(word_id) => Ok(Some(word_id)),
+            None => {
+                let fuzzy = self.fuzzy_word_id(spelling)?;
+                if let Some(word_id) = fuzzy {
+                    debug!("fuzzy match {:?} to {:?}", spelling, self.get_spelling(word_id)?);
+                }
+                Ok(fuzzy)
+            }
+        }
+    }
+
+    fn complete_any(&self, table: &str, select_field: &str,  filter: &[NamedParam]) -> Result<Option<i64>> {
+        match self.get_freq_where(table, select_field, filter) {
             Ok(Some(freq)) => {
                 let pick = random::<i64>().abs() % freq + 1;
-                self.get_next_word_filter(select_field, filter, pick)
+                self.get_next_word_filter(table, select_field, filter, pick)
             }
             result => result
         }
@@ -277,8 +766,12 @@ impl WordsDb {
     // This does not add new words so is only appropriate for feeding completion
     pub fn complete_id_vec(&self, prefix_words: &[&str]) -> Vec<i64> {
         prefix_words.iter().flat_map(|pword| {
-            let res = self.get_word_id(pword);
+            let res = self.lookup_word_id(pword);
             match res {
+                Ok(Some(word_id)) if self.blocked_id(word_id) => {
+                    debug!("not seeding from blocked {:?}", pword);
+                    vec![]
+                }
                 Ok(Some(word_id)) => vec![ word_id ],
                 Ok(None) => {
                     warn!("ignoring null value for {:?}", pword);
@@ -292,33 +785,53 @@ impl WordsDb {
         }).collect()
     }
 
-    fn complete_ids(&self, filter1: WordField, filter2: WordField, filter3: WordField, filter_values: Vec<i64>) -> ChainIter {
+    fn complete_ids(&self, filter1: WordField, filter2: WordField, filter3: WordField, filter_values: Vec<i64>, backoff: usize) -> ChainIter<'_> {
+        self.complete_kind_ids(PhraseKind::Said, [filter1, filter2, filter3], filter_values, backoff)
+    }
+
+    fn complete_kind_ids(&self, kind: PhraseKind, fields: [WordField; 3], filter_values: Vec<i64>, backoff: usize) -> ChainIter<'_> {
+        let [filter1, filter2, filter3] = fields;
         ChainIter {
             words: self,
             filter_fields: vec![filter1.into_str(), filter2.into_str(), filter3.into_str()],
             filter_values,
-            count: 0
+            table: kind.table(),
+            count: 0,
+            failed: false,
+            backoff,
+            last_backoff: 0
         }
     }
 
     fn complete_forward(&self, filter_values: Vec<i64>) -> ChainIter {
-        self.complete_ids(WordField::One, WordField::Two, WordField::Three, filter_values)
+        self.complete_ids(WordField::One, WordField::Two, WordField::Three, filter_values, self.config.backoff)
     }
     fn complete_backward(&self, filter_values: Vec<i64>) -> ChainIter {
-        self.complete_ids(WordField::Three, WordField::Two, WordField::One, filter_values)
+        self.complete_ids(WordField::Three, WordField::Two, WordField::One, filter_values, self.config.backoff)
     }
     // middle is intended for single lookup to prime other completions
     // for example:  1 2 3
     // Looking up (2) based on 1 and 3 isn't something that can reasonably chain further
+    // (so there's no backoff either, it would only chain on the last word)
     fn complete_middle(&self, filter_values: Vec<i64>) -> ChainIter {
-        self.complete_ids(WordField::One, WordField::Three, WordField::Two, filter_values)
+        self.complete_ids(WordField::One, WordField::Three, WordField::Two, filter_values, 0)
     }
 
     fn complete_and_map(&self, prefix: Vec<i64>) -> Result<Vec<String>> {
-        // filter based on the last two words in prefix
+        let ids = self.extend_forward(prefix)?;
+        self.map_spellings(ids)
+    }
+
+    // prefix followed by a forward chain from its last two words
+    fn extend_forward(&self, prefix: Vec<i64>) -> Result<Vec<i64>> {
         let filter = last_n(&prefix, 2);
-        let words = prefix.into_iter()
-                .chain(self.complete_forward(filter))
+        prefix.into_iter().map(Ok)
+            .chain(self.complete_forward(filter))
+            .collect()
+    }
+
+    fn map_spellings(&self, ids: Vec<i64>) -> Result<Vec<String>> {
+        let words = ids.into_iter()
                 .map(|id| self.get_spelling(id))
                 .collect::<Result<Vec<Option<String>>>>()?;
         Ok(words.into_iter().flatten().collect())
@@ -328,9 +841,9 @@ impl WordsDb {
         let sql = "select coalesce(sum(freq),0) from phrases where (word1=? and word2=?) or (word2=? and word3=?)";
         let res = self.db.query_row(sql, &[&w1,&w2,&w1,&w2], |row| row.get(0));
         match res {
-            Err(Error::QueryReturnedNoRows) => Ok(0),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
             Ok(count) => Ok(count),
-            Err(e) => Err(e)
+            Err(e) => Err(Error::from(e))
         }
     }
 
@@ -394,7 +907,7 @@ impl WordsDb {
         } else {
             let back_filter: Vec<i64> = primer.clone().into_iter().rev().collect();
             let back_iter = self.complete_backward(back_filter);
-            let back_words: Vec<i64> = back_iter.collect();
+            let back_words = back_iter.collect::<Result<Vec<i64>>>()?;
             back_words.into_iter().rev().chain(primer).collect()
         };
         self.complete_and_map(words)
@@ -410,14 +923,14 @@ impl WordsDb {
         let middle_word = if middle_filter.is_empty() {
             None
         } else {
-            self.complete_middle(middle_filter).next()
+            into_result(self.complete_middle(middle_filter).next().map(|r| r.map(Some)))?
         };
         if middle_word.is_some() {
             // filter is mid and at least one of first,last
             let filter: Vec<i64> = vec![first_word, middle_word, last_word].into_iter().flatten().collect();
             let back_filter: Vec<i64> = filter.clone().into_iter().take(2).collect::<Vec<i64>>().into_iter().rev().collect();
             let back_iter = self.complete_backward(back_filter);
-            let back_words: Vec<i64> = back_iter.collect();
+            let back_words = back_iter.collect::<Result<Vec<i64>>>()?;
             let back_words: Vec<i64> = back_words.into_iter().rev().chain(filter).collect();
             self.complete_and_map(back_words)
         } else {
@@ -426,15 +939,387 @@ impl WordsDb {
         }
     }
 
+    // Occurrences of each word: every learned word is the middle of
+    // exactly one trigram, so summing freq over word2 counts them.
+    // Both sums only read idx_phrases_word2.
+    fn count_word(&self, word_id: i64) -> Result<i64> {
+        Ok(self.db.query_row(
+            "select coalesce(sum(freq),0) from phrases where word2=?",
+            &[&word_id], |row| row.get(0))?)
+    }
+
+    fn count_all_words(&self) -> Result<i64> {
+        Ok(self.db.query_row(
+            "select coalesce(sum(freq),0) from phrases where word2<>0",
+            &[], |row| row.get(0))?)
+    }
+
+    /// Rank the known words of a phrase by inverse document frequency
+    ///
+    /// Returns (word, word_id, idf) with the most distinctive word first.
+    /// Unknown words are left out and repeated words are listed once.
+    pub fn rank_by_rarity<'a>(&self, phrase: &[&'a str]) -> Result<Vec<(&'a str, i64, f64)>> {
+        let total = self.count_all_words()?;
+        let mut ranked: Vec<(&str, i64, f64)> = Vec::with_capacity(phrase.len());
+        for word in phrase {
+            if word.is_empty() || ranked.iter().any(|(w, _, _)| w == word) {
+                continue;
+            }
+            if let Some(word_id) = self.lookup_word_id(word)? {
+                let count = self.count_word(word_id)?;
+                if count > 0 {
+                    let idf = (total as f64 / count as f64).ln();
+                    ranked.push((word, word_id, idf));
+                }
+            }
+        }
+        // stable, so ties keep message order
+        ranked.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal));
+        Ok(ranked)
+    }
+
+    /// An action around a random word of phrase that's been in one, or
+    /// any action when none has.  None until some actions are learned.
+    pub fn complete_action(&self, phrase: &[&str]) -> Result<Option<Vec<String>>> {
+        let kind = PhraseKind::Action;
+        if !self.exists(&format!("select 1 from {} limit 1", kind.table()), &[])? {
+            return Ok(None);
+        }
+        let acted = format!("select 1 from {} where word2=? limit 1", kind.table());
+        let mut seeds = vec![];
+        for word_id in self.complete_id_vec(phrase) {
+            if self.exists(&acted, &[&word_id])? {
+                seeds.push(word_id);
+            }
+        }
+        let backoff = self.config.backoff;
+        let prefix = if seeds.is_empty() {
+            vec![0]
+        } else {
+            let seed = seeds[random::<usize>() % seeds.len()];
+            let back = self.complete_kind_ids(kind, [WordField::Three, WordField::Two, WordField::One], vec![seed], backoff)
+                .collect::<Result<Vec<i64>>>()?;
+            back.into_iter().rev().chain(vec![seed]).collect()
+        };
+        let filter = last_n(&prefix, 2);
+        let ids = prefix.into_iter().map(Ok)
+            .chain(self.complete_kind_ids(kind, [WordField::One, WordField::Two, WordField::Three], filter, backoff))
+            .collect::<Result<Vec<i64>>>()?;
+        self.map_spellings(ids).map(Some)
+    }
+
+    fn exists(&self, sql: &str, params: &[&dyn ToSql]) -> Result<bool> {
+        match self.db.query_row(sql, params, |_| ()) {
+            Ok(()) => Ok(true),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    // grow a phrase outward in both directions from a single word
+    fn complete_around(&self, word_id: i64) -> Result<Vec<String>> {
+        let ids = self.around_ids(word_id)?;
+        self.map_spellings(ids)
+    }
+
+    fn around_ids(&self, word_id: i64) -> Result<Vec<i64>> {
+        let back_words = self.complete_backward(vec![word_id])
+            .collect::<Result<Vec<i64>>>()?;
+        let words = back_words.into_iter().rev().chain(vec![word_id]).collect();
+        self.extend_forward(words)
+    }
+
+    // a phrase ending in word_id, grown backward from the end sentinel
+    fn ending_ids(&self, word_id: i64) -> Result<Vec<i64>> {
+        let mut back_words = self.complete_backward(vec![0, word_id])
+            .collect::<Result<Vec<i64>>>()?;
+        back_words.reverse();
+        back_words.push(word_id);
+        Ok(back_words)
+    }
+
+    fn ends_phrase(&self, word_id: i64) -> Result<bool> {
+        let res = self.db.query_row(
+            "select 1 from phrases where word2=? and word3=0 limit 1",
+            &[&word_id], |_| ());
+        match res {
+            Ok(()) => Ok(true),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    fn known_word_id(&self, spelling: &str) -> Result<i64> {
+        self.get_word_id(spelling)?.ok_or_else(|| {
+            Error::Unsatisfiable(format!("{:?} is not a known word", spelling))
+        })
+    }
+
+    /// Generate a phrase that meets the constraints
+    ///
+    /// A phrase that must end with a word is grown backward from the end
+    /// of a phrase, otherwise it's grown in both directions around one of
+    /// the words it must contain.  Phrases are resampled until one has all
+    /// the required words, or attempts run out.
+    pub fn complete_constrained(&self, constraints: &Constraints, attempts: usize) -> Result<Vec<String>> {
+        let contains = constraints.contains.iter()
+            .map(|w| self.known_word_id(w))
+            .collect::<Result<Vec<i64>>>()?;
+        let ends_with = match &constraints.ends_with {
+            Some(w) => {
+                let word_id = self.known_word_id(w)?;
+                if !self.ends_phrase(word_id)? {
+                    return Err(Error::Unsatisfiable(format!("{:?} never ends a phrase", w)));
+                }
+                Some(word_id)
+            }
+            None => None
+        };
+        for attempt in 0..attempts {
+            let ids = match ends_with {
+                Some(end) => self.ending_ids(end)?,
+                None if !contains.is_empty() => {
+                    let pivot = contains[random::<usize>() % contains.len()];
+                    self.around_ids(pivot)?
+                }
+                None => self.extend_forward(vec![0])?
+            };
+            if contains.iter().all(|id| ids.contains(id)) {
+                debug!("phrase {} found after {} attempts", constraints, attempt + 1);
+                let ids = ids.into_iter().filter(|id| *id != 0).collect();
+                return self.map_spellings(ids);
+            }
+        }
+        Err(Error::Unsatisfiable(format!("no phrase {} found in {} attempts", constraints, attempts)))
+    }
+
+    /// Build a reply around the most distinctive known word in the phrase
+    ///
+    /// For "what do you think about rust compilers" this is likely to
+    /// start from "rust" or "compilers" rather than "what do".
+    /// If no word in the phrase is known, start a new phrase.
+    pub fn complete_from_rarest(&self, phrase: &[&str]) -> Result<Vec<String>> {
+        let ranked = self.rank_by_rarity(phrase)?;
+        self.complete_from_ranked(&ranked)
+    }
+
+    fn complete_from_ranked(&self, ranked: &[(&str, i64, f64)]) -> Result<Vec<String>> {
+        match ranked.first() {
+            Some((word, word_id, idf)) => {
+                debug!("seed from {:?} idf {}", word, idf);
+                self.complete_around(*word_id)
+            }
+            None => self.complete_and_map(vec![0])
+        }
+    }
+
+    /// Reply to a phrase using the configured seed mode
+    ///
+    /// phrase should already have the bot's nick removed, nearby is
+    /// the result of find_nearby and is used by the nearby mode, or
+    /// when none of the words in phrase are known.  Without nearby
+    /// words, as in a query that doesn't mention the bot, it's seeded
+    /// like an interjection.
+    pub fn complete_reply(&self, phrase: &[&str], nearby: Vec<Vec<&str>>) -> Result<Vec<String>> {
+        if self.config.seed_mode == SeedMode::Rare {
+            let ranked = self.rank_by_rarity(phrase)?;
+            if !ranked.is_empty() {
+                return self.complete_from_ranked(&ranked);
+            }
+        }
+        if nearby.is_empty() {
+            self.complete_interjection(phrase)
+        } else {
+            self.new_complete_middle_out(nearby)
+        }
+    }
+
+    // Unprompted reply, built middle out around a random word of phrase
+    pub fn complete_interjection(&self, phrase: &[&str]) -> Result<Vec<String>> {
+        let nearby = if phrase.is_empty() {
+            vec![vec![""]]
+        } else {
+            nearby_at(phrase, random::<usize>() % phrase.len())
+        };
+        self.new_complete_middle_out(nearby)
+    }
+
+    fn has_trigram(&self, w1: i64, w2: i64, w3: i64) -> Result<bool> {
+        let res = self.db.query_row(
+            "select 1 from phrases where word1=? and word2=? and word3=?",
+            &[&w1, &w2, &w3], |_| ());
+        match res {
+            Ok(()) => Ok(true),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    // does a span ending in context lead into the right hand words?
+    fn connects(&self, context: &[i64], right: &[i64]) -> Result<bool> {
+        match (context, right) {
+            ([a, b], [r1, r2, ..]) => Ok(self.has_trigram(*a, *b, *r1)? && self.has_trigram(*b, *r1, *r2)?),
+            ([a, b], [r1]) => self.has_trigram(*a, *b, *r1),
+            _ => Ok(false)
+        }
+    }
+
+    // Fill a blank between left and right context word ids.
+    // Sample forward walks from the left until one leads into the right,
+    // otherwise settle for a single word that fits between both sides.
+    fn bridge(&self, left: &[i64], right: &[i64]) -> Result<Vec<i64>> {
+        for _ in 0..BRIDGE_ATTEMPTS {
+            let mut span = vec![];
+            let mut context = chain_context(left);
+            for next in self.complete_forward(context.clone()).take(BRIDGE_MAX_SPAN) {
+                let id = next?;
+                if id == 0 {
+                    break;
+                }
+                span.push(id);
+                context = last_n(&[context.as_slice(), &[id]].concat(), 2);
+                if self.connects(&context, right)? {
+                    return Ok(span);
+                }
+            }
+        }
+        debug!("No bridge from {:?} to {:?}, try a middle word", left, right);
+        let around: Vec<i64> = left.last().into_iter().chain(right.first()).cloned().collect();
+        if let Some(middle) = self.complete_middle(around).next() {
+            return Ok(vec![middle?]);
+        }
+        // only consistent with the left side, but better than an empty blank
+        let forward = self.complete_forward(chain_context(left)).next();
+        Ok(into_result(forward.map(|r| r.map(Some)))?
+            .filter(|id| *id != 0)
+            .into_iter()
+            .collect())
+    }
+
+    /// Fill every _ in a template, keeping the fixed words in place
+    ///
+    /// For example "the _ is _ than a _".  A blank at the start is grown
+    /// backward from the words after it, a blank at the end is completed
+    /// forward to the end of a phrase, and a blank between fixed words is
+    /// bridged so the trigrams on both sides of it are known.
+    /// Fixed words missing from the database are kept, but act like
+    /// phrase boundaries for the blanks around them.
+    pub fn complete_template(&self, template: &[&str]) -> Result<Vec<String>> {
+        let parts = parse_template(template);
+        // word ids so far, starting at the phrase boundary
+        let mut ids: Vec<i64> = vec![0];
+        let mut words: Vec<String> = Vec::with_capacity(template.len());
+        for (pos, part) in parts.iter().enumerate() {
+            match part {
+                TemplatePart::Fixed(word) => {
+                    ids.push(self.get_word_id(word)?.unwrap_or(0));
+                    words.push(word.to_string());
+                }
+                TemplatePart::Blank => {
+                    // up to two fixed word ids after the blank, or the end of phrase
+                    let mut right: Vec<i64> = vec![];
+                    for next in &parts[pos + 1..] {
+                        match next {
+                            TemplatePart::Fixed(word) => {
+                                let id = self.get_word_id(word)?.unwrap_or(0);
+                                right.push(id);
+                                if id == 0 || right.len() == 2 {
+                                    break;
+                                }
+                            }
+                            TemplatePart::Blank => break
+                        }
+                    }
+                    if pos + 1 + right.len() >= parts.len() && right.len() < 2 {
+                        right.push(0);
+                    }
+                    let fill = match right.as_slice() {
+                        // trailing blank, finish the phrase
+                        [0] => self.complete_forward(chain_context(&ids))
+                            .collect::<Result<Vec<i64>>>()?,
+                        // leading blank, grow backward from the fixed words
+                        [r1, ..] if pos == 0 && *r1 != 0 => {
+                            let back_filter: Vec<i64> = right.iter().rev().cloned().collect();
+                            let mut back = self.complete_backward(back_filter)
+                                .collect::<Result<Vec<i64>>>()?;
+                            back.reverse();
+                            back
+                        }
+                        _ => self.bridge(&ids, &right)?
+                    };
+                    let fill: Vec<i64> = fill.into_iter().filter(|id| *id != 0).collect();
+                    if fill.is_empty() {
+                        warn!("Couldn't fill blank {} of {:?}", pos, template);
+                    }
+                    for id in fill {
+                        ids.push(id);
+                        words.extend(self.get_spelling(id)?);
+                    }
+                }
+            }
+        }
+        Ok(words)
+    }
+
+    /// Complete forward from prefix, recording the context and backoff
+    /// depth used for every word
+    pub fn explain_complete(&self, prefix: &[&str]) -> Result<Vec<Explained>> {
+        let filter = self.complete_id_vec(prefix);
+        let mut chain = self.complete_forward(filter);
+        let mut explained = vec![];
+        loop {
+            let context: Vec<i64> = chain.context().to_vec();
+            let word_id = match chain.next() {
+                Some(res) => res?,
+                None => break
+            };
+            let backoff = chain.last_backoff();
+            let context = context.into_iter().skip(backoff)
+                .map(|id| self.get_spelling(id))
+                .collect::<Result<Vec<Option<String>>>>()?;
+            explained.push(Explained {
+                word: self.get_spelling(word_id)?.unwrap_or_default(),
+                context: context.into_iter().flatten().collect(),
+                backoff
+            });
+        }
+        Ok(explained)
+    }
+
+    pub fn print_explain(&self, prefix: &[String]) {
+        let phrase: Vec<&str> = prefix.iter().map(AsRef::as_ref).collect();
+        match self.explain_complete(&phrase) {
+            Ok(explained) => {
+                for step in explained {
+                    println!("{:<16} <- {:?} (backoff {})", step.word, step.context, step.backoff);
+                }
+            }
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+
     pub fn complete(&self, prefix: &[&str] ) -> Result<Vec<String>> {
         let filter = self.complete_id_vec(prefix);
         let words = self.complete_forward(filter)
-                     .map(|id| self.get_spelling(id))
+                     .map(|id| self.get_spelling(id?))
                      .collect::<Result<Vec<Option<String>>>>()?;
         Ok(words.into_iter().flatten().collect())
     }
 
     pub fn print_complete(&self, prefix: &[String] ) {
+        match self.complete_args(prefix) {
+            Ok(words) => println!("{}", join_phrase(vec![], words)),
+            Err(Error::Unsatisfiable(msg)) => println!("{}", msg),
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+
+    // complete around _ in prefix, or fill each _ when there's more than one
+    pub fn complete_args(&self, prefix: &[String]) -> Result<Vec<String>> {
+        if prefix.iter().filter(|w| *w == "_").count() > 1 {
+            let template: Vec<&str> = prefix.iter().map(AsRef::as_ref).collect();
+            return self.complete_template(&template);
+        }
         let filter = if prefix.is_empty() {
             // no prefix, initialize from an end-of-phrase sentinel value,
             vec![vec![""]]
@@ -442,142 +1327,305 @@ impl WordsDb {
             let phrase: Vec<&str> = prefix.iter().map(AsRef::as_ref) .collect();
             find_nearby("_", phrase.as_slice())
         };
-
-        match filter.len() {
-            0 => println!("Couldn't find _ to complete against"),
-            _ => {
-                let result_words = self.new_complete_middle_out(filter);
-                match result_words {
-                    Ok(words) => println!("{}", join_phrase(vec![], words)),
-                    Err(e) => println!("Error: {:?}", e)
-                };
-            }
-        };
+        if filter.is_empty() {
+            return Err(Error::Unsatisfiable("Couldn't find _ to complete against".to_string()));
+        }
+        self.new_complete_middle_out(filter)
     }
 
     pub fn read_file(&mut self, filename: &str) -> Result<()> {
         if !self.config.learn_new_phrases {
-            error!("Abort, learning phrases is disabled by config");
-            // avoiding a new error type, return error
-            // similar to modifying a read-only database
-            return Err(rusqlite::Error::InvalidQuery);
+            return Err(Error::LearningDisabled);
         }
-        let res = fs::File::open(&filename);
-        let mut lines = 0;
-        match res {
-            Ok(file) => {
-                debug!("file: {:?}", file);
-                let tx = self.db.transaction()?;
-                let bufread = BufReader::new(&file);
-                for line_res in bufread.lines() {
-                    match line_res {
-                        Ok(line) => {
-                            // try to run this pattern in a test
-                            Self::add_line_db(&tx, &line)?;
+        let file = fs::File::open(filename)?;
+        let (mut lines, mut skipped) = (0, 0);
+        debug!("file: {:?}", file);
+        let tx = self.db.transaction()?;
+        let bufread = BufReader::new(&file);
+        for line_res in bufread.lines() {
+            match line_res {
+                Ok(line) => {
+                    let phrase: Vec<&str> = line.split_whitespace().collect();
+                    match self.config.filter.clean(&phrase) {
+                        Ok(tokens) => {
+                            let tokens: Vec<String> = tokens.iter().map(ToString::to_string).collect();
+                            Self::add_phrase_db(&tx, PhraseKind::Said, &tokens)?;
                             lines += 1;
                             if lines % 1000 == 0 {
                                 debug!("Added {} lines", lines);
                             }
                         }
-                        Err(e) => warn!("skipping: {:?}", e)
+                        Err(why) => {
+                            debug!("Not learning {:?}: {}", line, why);
+                            skipped += 1;
+                        }
                     }
                 }
-                tx.commit()?;
+                Err(e) => warn!("skipping: {:?}", e)
             }
-            Err(err) => error!("err: {:?}", err)
         }
-        info!("Added {} lines from {}", lines, filename);
-        Ok(())
+        tx.commit()?;
+        info!("Added {} lines from {}, filtered out {}", lines, filename, skipped);
+        // rare enough to check every word again
+        self.index_blocked()
     }
 
     // add line as a phrase, assuming string separated by whitespace
     pub fn add_line(&self, line: &str) -> Result<()> {
+        let words: Vec<String> = line.split_whitespace().map(ToString::to_string).collect();
+        self.add_phrase(&words)
+    }
+
+    pub fn add_phrase(&self, phrase: &[String] ) -> Result<()> {
         if self.config.learn_new_phrases {
-            Self::add_line_db(&self.db, line)
+            Self::add_phrase_db(&self.db, PhraseKind::Said, phrase)?;
+            self.index_learned_blocked(phrase)
         } else {
             Ok(())
         }
     }
 
-    fn add_line_db(db: &Connection, line: &str) -> Result<()> {
-        let words: Vec<String> = line.split_whitespace().map(ToString::to_string).collect();
-        Self::add_phrase_db(db, &words)
+    pub fn learning(&self) -> bool {
+        self.config.learn_new_phrases
     }
 
-    pub fn add_phrase(&self, phrase: &[String] ) -> Result<()> {
-        if self.config.learn_new_phrases {
-            Self::add_phrase_db(&self.db, phrase)
-        } else {
-            Ok(())
+    // switch learning at runtime, can't turn on for a read only database
+    pub fn set_learning(&mut self, learn: bool) -> Result<()> {
+        if learn && self.mode == OpenMode::ReadOnly {
+            return Err(Error::LearningDisabled);
         }
+        self.config.learn_new_phrases = learn;
+        Ok(())
     }
-    fn add_phrase_db(db: &Connection, phrase: &[String] ) -> Result<()> {
-        let v = Self::get_phrase_vec(db, phrase)?;
+
+    // remove a word and every trigram using it, returning the trigrams removed
+    pub fn forget_word(&self, spelling: &str) -> Result<usize> {
+        let word_id = match self.get_word_id(spelling)? {
+            Some(word_id) if word_id != 0 => word_id,
+            _ => return Ok(0)
+        };
+        migration::in_transaction(&self.db, || {
+            let mut forgotten = 0;
+            for kind in &[PhraseKind::Said, PhraseKind::Action] {
+                forgotten += self.db.execute(&format!(
+                    "delete from {} where word1=?1 or word2=?1 or word3=?1", kind.table()), &[&word_id])?;
+            }
+            self.db.execute("delete from word_forms where word_id=?", &[&word_id])?;
+            self.db.execute("delete from words where word_id=?", &[&word_id])?;
+            Ok(forgotten)
+        })
+    }
+
+    pub fn ignore_rules(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare("select rule from ignore_rules order by added_at, rule")?;
+        let rules = stmt.query_map(&[], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(rules)
+    }
+
+    pub fn add_ignore_rule(&self, rule: &str, added_by: &str) -> Result<()> {
+        self.db.execute("insert or ignore into ignore_rules (rule, added_by) values (?, ?)",
+            &[&rule, &added_by])?;
+        Ok(())
+    }
+
+    pub fn remove_ignore_rule(&self, rule: &str) -> Result<()> {
+        self.db.execute("delete from ignore_rules where rule=?", &[&rule])?;
+        Ok(())
+    }
+
+    // add the entries stored by block to those from the config
+    pub fn load_blocklist(&mut self) -> Result<()> {
+        let stored = self.stored_blocklist()?;
+        self.config.blocklist.load(stored);
+        self.index_blocked()
+    }
+
+    // find every known word the blocklist matches
+    fn index_blocked(&self) -> Result<()> {
+        let mut blocked = HashSet::new();
+        if !self.config.blocklist.is_empty() {
+            let mut stmt = self.db.prepare("select word_id, spelling from words where word_id<>0")?;
+            let mut rows = stmt.query(&[])?;
+            while let Some(row) = rows.next() {
+                let row = row?;
+                let spelling: String = row.get(1);
+                if self.config.blocklist.blocks(&spelling) {
+                    blocked.insert(row.get(0));
+                }
+            }
+        }
+        debug!("{} known words are blocked", blocked.len());
+        self.blocked.replace(blocked);
+        Ok(())
+    }
+
+    // words just learned that the blocklist matches
+    fn index_learned_blocked(&self, phrase: &[String]) -> Result<()> {
+        if self.config.blocklist.is_empty() {
+            return Ok(());
+        }
+        for spelling in phrase.iter().filter(|w| self.config.blocklist.blocks(w)) {
+            if let Some(word_id) = self.get_word_id(spelling)? {
+                self.blocked.borrow_mut().insert(word_id);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stored_blocklist(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare("select entry from blocklist order by added_at, entry")?;
+        let entries = stmt.query_map(&[], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(entries)
+    }
+
+    pub fn blocklist(&self) -> Vec<String> {
+        self.config.blocklist.entries()
+    }
+
+    /// Never say words matching entry from now on, storing it so it's
+    /// loaded again on start.  Returns the entry as stored.
+    pub fn block(&mut self, entry: &str, added_by: &str) -> Result<String> {
+        let entry = self.config.blocklist.add(entry)?;
+        let res = self.db.execute("insert or ignore into blocklist (entry, added_by) values (?, ?)",
+            &[&entry, &added_by]);
+        if let Err(e) = res {
+            self.config.blocklist.remove(&entry);
+            return Err(e.into());
+        }
+        self.index_blocked()?;
+        Ok(entry)
+    }
+
+    pub fn unblock(&mut self, entry: &str) -> Result<Removed> {
+        let removed = self.config.blocklist.remove(entry);
+        if removed == Removed::Removed {
+            self.db.execute("delete from blocklist where entry=?", &[&entry])?;
+            self.index_blocked()?;
+        }
+        Ok(removed)
+    }
+
+    // learn a phrase heard on channel, counting it in the stats
+    pub fn learn_from(&self, channel: &str, phrase: &[String]) -> Result<()> {
+        self.learn_kind_from(channel, phrase, PhraseKind::Said)
+    }
+
+    // a /me action, learned apart from what's said
+    pub fn learn_action_from(&self, channel: &str, phrase: &[String]) -> Result<()> {
+        self.learn_kind_from(channel, phrase, PhraseKind::Action)
+    }
+
+    fn learn_kind_from(&self, channel: &str, phrase: &[String], kind: PhraseKind) -> Result<()> {
+        if !self.config.learn_new_phrases {
+            return Ok(());
+        }
+        // CTCP delimiters left in a line would make replies CTCP queries
+        let phrase: Vec<String> = phrase.iter()
+            .map(|t| t.replace('\u{1}', ""))
+            .filter(|t| !t.is_empty())
+            .collect();
+        let tokens: Vec<&str> = phrase.iter().map(AsRef::as_ref).collect();
+        let tokens: Vec<String> = match self.config.filter.clean(&tokens) {
+            Ok(tokens) => tokens.iter().map(ToString::to_string).collect(),
+            Err(why) => {
+                debug!("Not learning {:?} from {}: {}", phrase, channel, why);
+                return Ok(());
+            }
+        };
+        migration::in_transaction(&self.db, || {
+            let learned = Self::add_phrase_db(&self.db, kind, &tokens)?;
+            stats::record(&self.db, channel, Counter::Lines, 1)?;
+            stats::record(&self.db, channel, Counter::NewWords, learned.new_words)?;
+            stats::record(&self.db, channel, Counter::NewTrigrams, learned.new_trigrams)
+        })?;
+        self.index_learned_blocked(&tokens)
+    }
+
+    fn add_phrase_db(db: &Connection, kind: PhraseKind, phrase: &[String] ) -> Result<Learned> {
+        let mut learned = Learned::default();
+        let v = Self::get_phrase_vec(db, phrase, &mut learned)?;
         let v1 = v.iter();
         let v2 = v.iter().skip(1);
         let v3 = v.iter().skip(2);
         for ((w1,w2),w3) in v1.zip(v2).zip(v3) {
-            Self::increment_frequency_db(db, &[w1,w2,w3])?;
+            if Self::increment_frequency_db(db, kind.table(), &[w1,w2,w3])? {
+                learned.new_trigrams += 1;
+            }
         }
-        Ok(())
+        Ok(learned)
     }
 
-    fn increment_frequency_db(db: &Connection, words: &[&dyn ToSql]) -> Result<usize> {
-        let sql = "select 1 from phrases where word1=? and word2=? and word3=?;";
-        let res: Result<i64> = db.query_row(sql, words, |row| row.get(0));
+    // true if the trigram wasn't known before
+    fn increment_frequency_db(db: &Connection, table: &str, words: &[&dyn ToSql]) -> Result<bool> {
+        let sql = format!("select 1 from {} where word1=? and word2=? and word3=?;", table);
+        let res: rusqlite::Result<i64> = db.query_row(&sql, words, |row| row.get(0));
         match res {
-            Err(Error::QueryReturnedNoRows) => {
-                let sql = "insert into phrases (freq, word1, word2, word3) values (1,?,?,?);";
-                db.execute(sql, words)
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                let sql = format!("insert into {} (freq, word1, word2, word3) values (1,?,?,?);", table);
+                db.execute(&sql, words)?;
+                Ok(true)
             },
             Ok(_) => {
-                let sql = "update phrases set freq=freq+1 where word1=? and word2=? and word3=?;";
-                db.execute(sql, words)
+                let sql = format!("update {} set freq=freq+1 where word1=? and word2=? and word3=?;", table);
+                db.execute(&sql, words)?;
+                Ok(false)
             },
-            Err(e) => Err(e)
+            Err(e) => Err(e.into())
         }
     }
 
     // lookup word ids and surround with begin/end 0s
-    fn get_phrase_vec(db: &Connection, phrase: &[String]) -> Result<Vec<i64>> {
-        let result = phrase.iter().map(
-            |w| Self::get_or_add_word_id(db, w))
-            .collect::<Result<Vec<i64>>>()?;
-        Ok(vec![0].into_iter().chain(result.into_iter()).chain(vec![0].into_iter()).collect())
+    fn get_phrase_vec(db: &Connection, phrase: &[String], learned: &mut Learned) -> Result<Vec<i64>> {
+        let mut result = vec![0];
+        for w in phrase {
+            let (word_id, new) = Self::get_or_add_word_id(db, w)?;
+            if new {
+                learned.new_words += 1;
+            }
+            result.push(word_id);
+        }
+        result.push(0);
+        Ok(result)
     }
 
-    fn get_freq_where(&self, filter: &[NamedParam]) -> Result<Option<i64>> {
-        let wheres = NamedParam::assigns(filter);
-        let values = NamedParam::values(filter);
-        let sql_where = if wheres.as_slice().is_empty() {
+    // filter on the context, leaving out blocked words in select_field
+    fn sql_where(&self, select_field: &str, filter: &[NamedParam]) -> String {
+        let mut wheres = NamedParam::assigns(filter);
+        let blocked = self.blocked.borrow();
+        if !blocked.is_empty() {
+            let ids: Vec<String> = blocked.iter().map(ToString::to_string).collect();
+            wheres.push(format!("{} not in ({})", select_field, ids.join(",")));
+        }
+        if wheres.is_empty() {
             String::from("")
         } else {
-            format!("where {}", wheres.join(&String::from(" and ")))
-        };
-        let sql = format!("select sum(freq) from phrases {}", sql_where);
+            format!("where {}", wheres.join(" and "))
+        }
+    }
+
+    fn get_freq_where(&self, table: &str, select_field: &str, filter: &[NamedParam]) -> Result<Option<i64>> {
+        let values = NamedParam::values(filter);
+        let sql = format!("select sum(freq) from {} {}", table, self.sql_where(select_field, filter));
 
         no_rows_as_none(self.db.query_row(&sql, values.as_slice(),
             |row| row.get_checked(0)))
     }
 
-    fn get_next_word_filter(&self, select_field: &str, prefix_filter: &[NamedParam], pick: i64)
+    fn get_next_word_filter(&self, table: &str, select_field: &str, prefix_filter: &[NamedParam], pick: i64)
         -> Result<Option<i64>> {
-        let wheres = NamedParam::assigns(prefix_filter);
         let values = NamedParam::values(prefix_filter);
         // retrieve column based on how many words in prefix
-        let sql_where = if prefix_filter.is_empty() {
-            "".to_string()
-        } else {
-            format!("where {}", wheres.join(&String::from(" and ")))
-        };
+        let sql_where = self.sql_where(select_field, prefix_filter);
         let sql = format!(
             // note: this code was lightly tested, but it seems
             //       that summing in sqlite engine is actually slower
             //       than in rust
             // "select sum(freq), {} from phrases {} group by {}",
             // select_field, sql_where, select_field);
-            "select freq, {} from phrases {}",
-            select_field, sql_where);
+            "select freq, {} from {} {}",
+            select_field, table, sql_where);
 
         let mut pick_count: i64 = pick;
         let mut stmt = self.db.prepare(&sql)?;
@@ -595,19 +1643,26 @@ impl WordsDb {
         Ok(None)
     }
 
-    fn get_or_add_word_id(db: &Connection, spelling: &str) -> Result<i64> {
+    fn blocked_id(&self, word_id: i64) -> bool {
+        self.blocked.borrow().contains(&word_id)
+    }
+
+    // word id, and whether it was just added
+    fn get_or_add_word_id(db: &Connection, spelling: &str) -> Result<(i64, bool)> {
         let res = Self::get_word_id_db(db, spelling);
         match res {
             Ok(None) => {
                 db.execute("insert into words (spelling) values (?)", &[&spelling])?;
-                Ok(db.last_insert_rowid())
+                let word_id = db.last_insert_rowid();
+                Self::add_word_forms_db(db, word_id, spelling)?;
+                Ok((word_id, true))
             },
-            Ok(Some(word_id)) => Ok(word_id),
+            Ok(Some(word_id)) => Ok((word_id, false)),
             Err(e) => Err(e)
         }
     }
 
-    fn get_word_id(&self, spelling: &str) -> Result<Option<i64>> {
+    pub(crate) fn get_word_id(&self, spelling: &str) -> Result<Option<i64>> {
         Self::get_word_id_db(&self.db, spelling)
     }
     fn get_word_id_db(db: &Connection, spelling: &str) -> Result<Option<i64>> {
@@ -627,9 +1682,10 @@ impl WordsDb {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::options::irc_config;
 
     fn memdb() -> WordsDb {
-        WordsDb::new(":memory:".to_string())
+        WordsDb::new(":memory:".to_string()).expect("open")
     }
     fn abcde() -> WordsDb {
         let w = memdb();
@@ -653,8 +1709,8 @@ mod tests {
         c.summary()
     }
     fn assert_next(words: &WordsDb, chain: &mut ChainIter, expected: &str) {
-        let got = chain.next().map(|id| words.get_spelling(id));
-        assert_eq!(got.unwrap().unwrap().unwrap(), expected);
+        let id = chain.next().expect("chain ended").expect("chain failed");
+        assert_eq!(words.get_spelling(id).unwrap().unwrap(), expected);
     }
     #[test]
     fn forward1() {
@@ -693,8 +1749,18 @@ mod tests {
         let filter = w.complete_id_vec(&vec!["b","d"]);
         let mut chain = w.complete_middle(filter);
         assert_next(&w, &mut chain, "c");
-        let none = chain.next();
-        assert_eq!(None, none);
+        assert!(chain.next().is_none());
+    }
+    #[test]
+    fn chain_error_ends_chain() {
+        // without migration there's no phrases table to query
+        let w = memdb();
+        let mut chain = w.complete_forward(vec![0]);
+        match chain.next() {
+            Some(Err(Error::Storage(_))) => (),
+            other => panic!("expected storage error, got {:?}", other)
+        }
+        assert!(chain.next().is_none());
     }
     #[test]
     fn complete_and_map() {
@@ -702,6 +1768,277 @@ mod tests {
         let complete: Vec<String> = w.complete_and_map(vec![0]).unwrap();
         assert_eq!(vec!["","a","b","c","d","e",""], complete);
     }
+    fn rusty() -> WordsDb {
+        let w = memdb();
+        w.migrate().expect("migrate");
+        w.add_line("the cat sat").expect("read line");
+        w.add_line("the dog sat").expect("read line");
+        w.add_line("the rust compiler is slow").expect("read line");
+        w
+    }
+    #[test]
+    fn rank_by_rarity() {
+        let w = rusty();
+        let ranked = w.rank_by_rarity(&["what", "the", "rust", "sat", "the"]).unwrap();
+        let words: Vec<&str> = ranked.iter().map(|(word, _, _)| *word).collect();
+        assert_eq!(vec!["rust", "sat", "the"], words);
+        // word counts don't scan phrases
+        let plan: String = w.db.query_row(
+            "explain query plan select coalesce(sum(freq),0) from phrases where word2=?",
+            &[&1], |row| row.get(3)).unwrap();
+        assert!(plan.contains("idx_phrases_word2"), "{}", plan);
+    }
+    #[test]
+    fn complete_from_rarest() {
+        let w = rusty();
+        let complete = w.complete_from_rarest(&["what", "about", "the", "rust"]).unwrap();
+        assert_eq!(vec!["", "the", "rust", "compiler", "is", "slow", ""], complete);
+    }
+    #[test]
+    fn complete_from_rarest_unknown() {
+        let w = rusty();
+        let complete = w.complete_from_rarest(&["nothing", "known"]).unwrap();
+        assert_eq!(Some(&"".to_string()), complete.first());
+    }
+    #[test]
+    fn seed_mode_parse() {
+        assert_eq!(SeedMode::Rare, "rare".parse().unwrap());
+        assert_eq!(SeedMode::Nearby, "nearby".parse().unwrap());
+        assert!("rarest".parse::<SeedMode>().is_err());
+    }
+    fn comparisons() -> WordsDb {
+        let w = memdb();
+        w.migrate().expect("migrate");
+        w.add_line("the cat is bigger than a mouse").expect("read line");
+        w.add_line("a dog is smaller than a horse").expect("read line");
+        w
+    }
+    #[test]
+    fn parse_template() {
+        use super::TemplatePart::*;
+        let parts = super::parse_template(&["_", "is", "_", "_", "a"]);
+        assert_eq!(vec![Blank, Fixed("is"), Blank, Fixed("a")], parts);
+    }
+    #[test]
+    fn complete_template() {
+        let w = comparisons();
+        let words = w.complete_template(&["the", "_", "is", "_", "than", "a", "_"]).unwrap();
+        assert_eq!(vec!["the", "cat", "is", "bigger", "than", "a"], &words[..6]);
+        assert_eq!(7, words.len());
+        assert!(words[6] == "mouse" || words[6] == "horse");
+    }
+    #[test]
+    fn complete_template_leading_blank() {
+        let w = comparisons();
+        let words = w.complete_template(&["_", "smaller", "_"]).unwrap();
+        assert_eq!(vec!["a", "dog", "is", "smaller", "than", "a"], &words[..6]);
+    }
+    #[test]
+    fn complete_template_unknown_word() {
+        let w = comparisons();
+        let words = w.complete_template(&["zebra", "_", "_"]).unwrap();
+        assert_eq!("zebra", words[0]);
+        assert!(words.len() > 1);
+    }
+    #[test]
+    fn constrained_ends_with() {
+        let w = comparisons();
+        let constraints = Constraints { ends_with: Some("horse".to_string()), .. Default::default() };
+        let words = w.complete_constrained(&constraints, 1).unwrap();
+        assert_eq!(Some(&"horse".to_string()), words.last());
+        assert!(words.contains(&"than".to_string()));
+    }
+    #[test]
+    fn constrained_contains_and_ends_with() {
+        let w = comparisons();
+        let constraints = Constraints {
+            contains: vec!["cat".to_string()],
+            ends_with: Some("horse".to_string())
+        };
+        let words = w.complete_constrained(&constraints, CONSTRAINT_ATTEMPTS).unwrap();
+        assert_eq!("the cat is bigger than a horse", join_phrase(vec![], words));
+    }
+    #[test]
+    fn constrained_unsatisfiable() {
+        let w = comparisons();
+        let never_ends = Constraints { ends_with: Some("the".to_string()), .. Default::default() };
+        let unknown = Constraints { contains: vec!["zebra".to_string()], .. Default::default() };
+        let apart = Constraints { contains: vec!["cat".to_string(), "dog".to_string()], .. Default::default() };
+        for constraints in &[never_ends, unknown, apart] {
+            match w.complete_constrained(constraints, 10) {
+                Err(Error::Unsatisfiable(_)) => (),
+                other => panic!("expected unsatisfiable for {}, got {:?}", constraints, other)
+            }
+        }
+    }
+    #[test]
+    fn backoff_unseen_pair() {
+        let w = abcde();
+        let words = w.complete(&["a", "c"]).unwrap();
+        assert_eq!(vec!["d", "e", ""], words);
+    }
+    #[test]
+    fn no_backoff_unseen_pair() {
+        let mut w = abcde();
+        w.config.backoff = 0;
+        let words = w.complete(&["a", "c"]).unwrap();
+        assert!(words.is_empty());
+    }
+    #[test]
+    fn backoff_keeps_last_word() {
+        // e only ends a phrase, so there's nothing to say after it
+        let mut w = abcde();
+        w.add_line("x y z").expect("read line");
+        assert!(w.complete(&["e"]).unwrap().is_empty());
+        // unless backing off to any word at all
+        w.config.backoff = MAX_BACKOFF;
+        assert!(!w.complete(&["e"]).unwrap().is_empty());
+    }
+    #[test]
+    fn explain_backoff() {
+        let w = abcde();
+        let explained = w.explain_complete(&["a", "c"]).unwrap();
+        assert_eq!("d", explained[0].word);
+        assert_eq!(vec!["c"], explained[0].context);
+        assert_eq!(1, explained[0].backoff);
+        assert_eq!("e", explained[1].word);
+        assert_eq!(vec!["c", "d"], explained[1].context);
+        assert_eq!(0, explained[1].backoff);
+    }
+    #[test]
+    fn fold_and_stem() {
+        assert_eq!("rust", fold_spelling("Rust?"));
+        assert_eq!("...", fold_spelling("..."));
+        assert_eq!("compiler", stem_spelling("compilers"));
+        assert_eq!("library", stem_spelling("libraries"));
+        assert_eq!("compil", stem_spelling("compiling"));
+        assert_eq!("class", stem_spelling("class"));
+    }
+    #[test]
+    fn edit_distances() {
+        assert_eq!(0, edit_distance("rust", "rust"));
+        assert_eq!(1, edit_distance("rust", "rsut"));
+        assert_eq!(1, edit_distance("compiler", "compilr"));
+        assert_eq!(3, edit_distance("", "abc"));
+    }
+    #[test]
+    fn fuzzy_word_id() {
+        let w = rusty();
+        let id = |word: &str| w.get_word_id(word).unwrap();
+        assert_eq!(id("rust"), w.fuzzy_word_id("RUST!").unwrap());
+        assert_eq!(id("compiler"), w.fuzzy_word_id("compilers").unwrap());
+        assert_eq!(id("compiler"), w.fuzzy_word_id("comp").unwrap());
+        assert_eq!(id("compiler"), w.fuzzy_word_id("compilre").unwrap());
+        assert_eq!(None, w.fuzzy_word_id("zebra").unwrap());
+        assert_eq!(None, w.fuzzy_word_id("it").unwrap());
+    }
+    #[test]
+    fn fuzzy_threshold() {
+        let mut w = rusty();
+        w.config.fuzzy = 0;
+        assert_eq!(None, w.fuzzy_word_id("compilre").unwrap());
+        assert_eq!(1, w.complete_id_vec(&["Slow"]).len());
+    }
+    #[test]
+    fn index_word_forms_on_migrate() {
+        let w = rusty();
+        w.db.execute("delete from word_forms", &[]).unwrap();
+        w.migrate().expect("migrate");
+        assert_eq!(w.get_word_id("rust").unwrap(), w.fuzzy_word_id("Rust").unwrap());
+    }
+    // a database file that's removed when dropped
+    struct TempDb(String);
+    impl TempDb {
+        fn new(name: &str) -> TempDb {
+            let path = env::temp_dir().join(format!("bazbot-{}-{}.db", name, std::process::id()));
+            TempDb(path.to_string_lossy().to_string())
+        }
+    }
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            for suffix in &["", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+    #[test]
+    fn wal_mode() {
+        let tmp = TempDb::new("wal");
+        let w = WordsDb::new(tmp.0.clone()).expect("open");
+        let journal: String = w.db.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).unwrap();
+        assert_eq!("wal", journal);
+    }
+    #[test]
+    fn read_only_while_writing() {
+        let tmp = TempDb::new("readonly");
+        let writer = WordsDb::new(tmp.0.clone()).expect("open");
+        writer.migrate().expect("migrate");
+        writer.add_line("a b c").expect("add");
+        let config = irc_config(&[("words", &tmp.0)]);
+        let reader = WordsDb::from_config(&config, OpenMode::ReadOnly).expect("open read only");
+        writer.db.execute_batch("begin immediate; insert into words (spelling) values ('d');")
+            .expect("write lock");
+        assert_eq!(vec!["c", ""], reader.complete(&["a", "b"]).expect("read during write"));
+        match reader.add_line("x y z") {
+            Err(Error::Storage(_)) => (),
+            other => panic!("expected read only error, got {:?}", other)
+        }
+        writer.db.execute_batch("commit;").expect("commit");
+    }
+    #[test]
+    fn status_of_missing_file() {
+        let tmp = TempDb::new("missing");
+        let config = WordsConfig::from_irc_config(&irc_config(&[("words", &tmp.0)])).expect("config");
+        let w = WordsDb::open_or_empty(config).expect("open");
+        assert!(w.migration_status().expect("status").iter()
+            .all(|s| s.state == migration::MigrationState::Pending));
+        assert!(!Path::new(&tmp.0).exists());
+        // an existing file is read as it is
+        WordsDb::new(tmp.0.clone()).expect("create").migrate().expect("migrate");
+        let config = WordsConfig::from_irc_config(&irc_config(&[("words", &tmp.0)])).expect("config");
+        let w = WordsDb::open_or_empty(config).expect("open");
+        assert!(w.migration_status().expect("status").iter()
+            .all(|s| s.state == migration::MigrationState::Applied));
+    }
+    #[test]
+    fn no_learn_opens_read_only() {
+        let tmp = TempDb::new("nolearn");
+        WordsDb::new(tmp.0.clone()).expect("create").migrate().expect("migrate");
+        let config = irc_config(&[("words", &tmp.0), ("learn", "false")]);
+        let w = WordsDb::from_config(&config, OpenMode::ReadWrite).expect("open");
+        assert_eq!(OpenMode::ReadOnly, w.mode());
+        w.migrate().expect("skipped");
+    }
+    #[test]
+    fn interjection_from_line() {
+        let w = abcde();
+        for _ in 0..5 {
+            assert_eq!(vec!["a", "b", "c", "d", "e"], w.complete_interjection(&["b", "c", "d"]).expect("interject")
+                .iter().filter(|s| !s.is_empty()).collect::<Vec<_>>());
+        }
+    }
+    #[test]
+    fn reply_without_nearby() {
+        let w = abcde();
+        assert_eq!(vec!["a", "b", "c", "d", "e"], w.complete_reply(&["c"], vec![]).expect("reply")
+            .iter().filter(|s| !s.is_empty()).collect::<Vec<_>>());
+    }
+    #[test]
+    fn learn_counts_stats() {
+        let w = WordsDb::new(":memory:".to_string()).expect("open");
+        w.migrate().expect("migrate");
+        let phrase = |line: &str| -> Vec<String> { line.split_whitespace().map(ToString::to_string).collect() };
+        w.learn_from("#a", &phrase("a b c")).expect("learn");
+        w.learn_from("#a", &phrase("a b d")).expect("learn");
+        w.record_stat("#a", Counter::Replies, 1).expect("record");
+        let today = w.stats_history(1, Some("#a")).expect("history");
+        assert_eq!(1, today.len());
+        assert_eq!(2, today[0].get(Counter::Lines));
+        assert_eq!(4, today[0].get(Counter::NewWords));
+        // the second line shares its first trigram with the first
+        assert_eq!(3 + 2, today[0].get(Counter::NewTrigrams));
+        assert_eq!(1, today[0].get(Counter::Replies));
+    }
     #[test]
     fn search_middle() {
         let filter = find_nearby("baz", &tokenize_phrase("a b baz d e"));
@@ -728,6 +2065,13 @@ mod tests {
         let empty: Vec<Vec<&str>> = vec![];
         assert_eq!(empty, filter);
     }
+    #[test]
+    fn nearby_exact_position() {
+        // interjections seed from one token, not the first word it prefixes
+        let phrase = tokenize_phrase("and a b");
+        assert_eq!(vec![vec!["", "and"], vec!["b", ""]], nearby_at(&phrase, 1));
+        assert_eq!(vec![vec!["a", "b"]], find_nearby("a", &phrase));
+    }
 
     #[test]
     fn add_phrase_without_learn() {
@@ -737,4 +2081,89 @@ mod tests {
         assert_eq!(vec![""], complete.expect("successful none"));
     }
 
+    #[test]
+    fn read_file_without_learn() {
+        let mut w = no_learn();
+        match w.read_file("no-such-file.txt") {
+            Err(Error::LearningDisabled) => (),
+            other => panic!("expected learning disabled, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn never_say_blocked() {
+        let mut w = memdb();
+        w.migrate().expect("migrate");
+        w.add_line("a darn c").expect("read line");
+        w.add_line("a b c").expect("read line");
+        w.block("Darn", "test").expect("block");
+        for _ in 0..10 {
+            assert_eq!(vec!["b", "c"], w.complete(&["a"]).expect("complete")
+                .iter().filter(|s| !s.is_empty()).collect::<Vec<_>>());
+        }
+        // seeds from the message skip it too
+        assert_eq!(Vec::<i64>::new(), w.complete_id_vec(&["darn"]));
+        // words learned after blocking are blocked as they're learned
+        w.block("/^heck/", "test").expect("block");
+        w.add_line("a heckin c").expect("read line");
+        for _ in 0..10 {
+            assert_eq!(Some(&"b".to_string()), w.complete(&["a"]).expect("complete").first());
+        }
+        // and unblocked words come back
+        w.unblock("Darn").expect("unblock");
+        assert!((0..100).any(|_| w.complete(&["a"]).expect("complete").first() == Some(&"darn".to_string())));
+    }
+
+    #[test]
+    fn learn_actions() {
+        let w = WordsDb::new(":memory:".to_string()).expect("open");
+        w.migrate().expect("migrate");
+        let phrase = |line: &str| -> Vec<String> { line.split_whitespace().map(ToString::to_string).collect() };
+        assert_eq!(None, w.complete_action(&["waves"]).expect("no actions"));
+        w.learn_action_from("#a", &phrase("waves at everyone")).expect("learn");
+        w.learn_from("#a", &phrase("everyone said hi")).expect("learn");
+        // actions and speech don't chain into each other
+        assert!(w.complete(&["waves"]).expect("complete").is_empty());
+        assert_eq!(vec!["", "everyone", "said", "hi", ""], w.complete_interjection(&["everyone"]).expect("interject"));
+        let action = w.complete_action(&["everyone", "unknown"]).expect("action");
+        assert_eq!(Some(vec!["", "waves", "at", "everyone", ""]), action.as_ref().map(|a| a.iter().map(String::as_str).collect()));
+        // with no word in an action, any action will do
+        assert!(w.complete_action(&["said"]).expect("action").is_some());
+    }
+
+    #[test]
+    fn strip_ctcp_delimiters() {
+        let w = WordsDb::new(":memory:".to_string()).expect("open");
+        w.migrate().expect("migrate");
+        let phrase = |line: &str| -> Vec<String> { line.split_whitespace().map(ToString::to_string).collect() };
+        w.learn_from("#a", &phrase("\u{1}ACTION not \u{1} really\u{1}")).expect("learn");
+        assert_eq!(vec!["", "ACTION", "not", "really", ""], w.complete_interjection(&["not"]).expect("interject"));
+    }
+
+    #[test]
+    fn read_filtered_file() {
+        let mut w = memdb();
+        w.migrate().expect("migrate");
+        let corpus = TempDb::new("corpus");
+        fs::write(&corpus.0, "!seen joe\nthe cat sat\nlol\nsee https://example.com now\n").expect("write");
+        w.read_file(&corpus.0).expect("read");
+        assert!(w.get_word_id("cat").unwrap().is_some());
+        assert!(w.get_word_id("now").unwrap().is_some());
+        assert_eq!(None, w.get_word_id("!seen").unwrap());
+        assert_eq!(None, w.get_word_id("lol").unwrap());
+        assert_eq!(None, w.get_word_id("https://example.com").unwrap());
+        w.learn_from("#a", &["!seen".to_string(), "ann".to_string()]).expect("learn");
+        assert_eq!(None, w.get_word_id("ann").unwrap());
+    }
+
+    #[test]
+    fn read_missing_file() {
+        let mut w = memdb();
+        w.migrate().expect("migrate");
+        match w.read_file("no-such-file.txt") {
+            Err(Error::Io(_)) => (),
+            other => panic!("expected i/o error, got {:?}", other)
+        }
+    }
+
 }
@@ -1,17 +1,34 @@
-
-use rusqlite::{Result, Connection,Error};
+use rusqlite::Connection;
 use rusqlite::types::ToSql;
+use crate::error::{Error, Result};
 
 #[derive(Debug)]
 pub struct Migration {
     pub m_id: &'static str,
     pub m_sql: &'static str,
+    // reverses m_sql for migrate down, None if it can't be undone
+    pub m_down: Option<&'static str>,
+}
+
+impl Migration {
+    // fnv-1a of the sql with whitespace collapsed, so re-indenting
+    // a migration doesn't count as changing it
+    pub fn checksum(&self) -> String {
+        let normalized = self.m_sql.split_whitespace().collect::<Vec<&str>>().join(" ");
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in normalized.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        format!("{:016x}", hash)
+    }
 }
 
 pub fn base() -> Migration {
     Migration {
         m_id: "init",
-        m_sql: "create table migrations ( m_id primary key );"
+        m_sql: "create table migrations ( m_id primary key );",
+        m_down: None
     }
 }
 
@@ -30,7 +47,9 @@ pub fn migrations() -> Vec<Migration> {
         insert into words (word_id, spelling) values (0,'');
         CREATE UNIQUE INDEX idx_words on words (word_id);
         CREATE UNIQUE INDEX idx_spelling on words (spelling);
-        CREATE UNIQUE INDEX idx_phrases_u on phrases (word1,word2,word3);"
+        CREATE UNIQUE INDEX idx_phrases_u on phrases (word1,word2,word3);",
+        // rolling back would throw away everything learned
+        m_down: None
     },
     Migration {
         m_id: "phrases_spelling_view",
@@ -41,21 +60,212 @@ pub fn migrations() -> Vec<Migration> {
         inner join words w1 on phrases.word1 = w1.word_id
         inner join words w2 on phrases.word2 = w2.word_id
         inner join words w3 on phrases.word3 = w3.word_id;
-        "
+        ",
+        m_down: Some("drop view phrases_spelling;")
     },
     Migration {
         m_id: "idx_phrases_backward",
         // leave out the middle search index because it's only called
         // once per completion, and can leverage idx_phrases_u
-        m_sql: "create index idx_phrases_backward on phrases(word3, word2);"
+        m_sql: "create index idx_phrases_backward on phrases(word3, word2);",
+        m_down: Some("drop index idx_phrases_backward;")
+    },
+    Migration {
+        m_id: "word_forms",
+        // looser spellings of each word for fuzzy lookups, filled in
+        // from rust because sqlite can't stem or fold punctuation
+        m_sql: "
+        CREATE TABLE word_forms (
+            word_id integer primary key,
+            folded text not null,
+            stem text not null,
+            len integer not null,
+            foreign key (word_id) references words(word_id)
+        );
+        CREATE INDEX idx_word_forms_folded on word_forms (folded);
+        CREATE INDEX idx_word_forms_stem on word_forms (stem);
+        CREATE INDEX idx_word_forms_len on word_forms (len, folded);",
+        m_down: Some("drop table word_forms;")
+    },
+    Migration {
+        m_id: "migrations_checksum",
+        // migrations applied before this get their checksum filled in
+        // by the next migrate up, trusting what's already there
+        m_sql: "alter table migrations add column checksum text;",
+        // sqlite can't drop columns everywhere, so copy the table without
+        // it, in rowid order since rollback undoes the newest first
+        m_down: Some("
+        create table migrations_old ( m_id primary key );
+        insert into migrations_old (m_id) select m_id from migrations order by rowid;
+        drop table migrations;
+        alter table migrations_old rename to migrations;")
+    },
+    Migration {
+        m_id: "migrations_down_sql",
+        // keep the reverse sql in the database, so an older bazbot
+        // can roll back migrations it doesn't know about
+        m_sql: "alter table migrations add column down_sql text;",
+        m_down: Some("
+        create table migrations_old ( m_id primary key, checksum text );
+        insert into migrations_old (m_id, checksum) select m_id, checksum from migrations order by rowid;
+        drop table migrations;
+        alter table migrations_old rename to migrations;")
+    },
+    Migration {
+        m_id: "stats",
+        // activity counters per day (UTC) and channel
+        m_sql: "
+        CREATE TABLE stats (
+            day text not null,
+            channel text not null,
+            counter text not null,
+            count integer not null,
+            primary key (day, channel, counter)
+        );",
+        m_down: Some("drop table stats;")
+    },
+    Migration {
+        m_id: "ignore_rules",
+        // ignore rules added by admins on irc, config rules aren't stored
+        m_sql: "
+        CREATE TABLE ignore_rules (
+            rule text primary key,
+            added_by text not null,
+            added_at text not null default (datetime('now'))
+        );",
+        m_down: Some("drop table ignore_rules;")
+    },
+    Migration {
+        m_id: "blocklist",
+        // words the bot won't say, added by admins; config entries aren't stored
+        m_sql: "
+        CREATE TABLE blocklist (
+            entry text primary key,
+            added_by text not null,
+            added_at text not null default (datetime('now'))
+        );",
+        m_down: Some("drop table blocklist;")
+    },
+    Migration {
+        m_id: "idx_phrases_word2",
+        // word counts for rare seeding sum freq by word2, covered by this
+        m_sql: "create index idx_phrases_word2 on phrases(word2, freq);",
+        m_down: Some("drop index idx_phrases_word2;")
+    },
+    Migration {
+        m_id: "action_phrases",
+        // trigrams of /me actions, chained apart from what's said
+        m_sql: "
+        CREATE TABLE action_phrases (
+            word1 integer not null, word2 integer not null, word3 integer not null, freq integer not null,
+            foreign key (word1) references words(word_id),
+            foreign key (word2) references words(word_id),
+            foreign key (word3) references words(word_id)
+        );
+        CREATE UNIQUE INDEX idx_action_phrases_u on action_phrases (word1,word2,word3);
+        CREATE INDEX idx_action_phrases_backward on action_phrases (word3, word2);",
+        m_down: Some("drop table action_phrases;")
     }]
 }
 
+// Schema version stored in PRAGMA user_version: the number of migrations
+// applied.  A version above migrations().len() was written by a newer bazbot.
+pub fn schema_version(db: &Connection) -> Result<i64> {
+    Ok(db.query_row("PRAGMA user_version", &[], |row| row.get(0))?)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    // applied before checksums were recorded
+    Unverified,
+    // applied, but the sql in this binary is different
+    Changed,
+    Pending,
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub migration: Migration,
+    pub state: MigrationState,
+}
+
+// apply all pending migrations
 pub fn migrate(db: &Connection) -> Result<()> {
     let m = Migrator::new(db);
     m.migrate()
 }
 
+pub fn status(db: &Connection) -> Result<Vec<MigrationStatus>> {
+    Migrator::new(db).status()
+}
+
+// migrations applied to the database that this binary doesn't know
+pub fn unknown(db: &Connection) -> Result<Vec<String>> {
+    let known = migrations();
+    Ok(Migrator::new(db).applied()?.into_iter()
+        .map(|(m_id, _)| m_id)
+        .filter(|m_id| m_id != base().m_id && !known.iter().any(|m| m.m_id == m_id))
+        .collect())
+}
+
+// roll back the most recently applied migration, returning its id
+pub fn rollback(db: &Connection) -> Result<Option<String>> {
+    Migrator::new(db).rollback()
+}
+
+// check the database is fully migrated by this binary, without writing
+pub fn verify(db: &Connection) -> Result<()> {
+    check_not_newer(db)?;
+    let statuses = Migrator::new(db).status()?;
+    check_changed(&statuses)?;
+    let pending = statuses.iter().filter(|s| s.state == MigrationState::Pending).count();
+    if pending > 0 {
+        return Err(Error::Migration(format!(
+            "{} pending migrations, run `bazbot migrate up` (or `migrate plan` to see them)",
+            pending)));
+    }
+    Ok(())
+}
+
+fn check_not_newer(db: &Connection) -> Result<()> {
+    let unknown = unknown(db)?;
+    let version = schema_version(db)?;
+    let known = migrations().len() as i64;
+    if !unknown.is_empty() || version > known {
+        return Err(Error::Migration(format!(
+            "database schema version {} is newer than this bazbot understands ({}), \
+             unknown migrations: {}. Use a newer bazbot, or roll back with `bazbot migrate down`",
+            version, known, unknown.join(", "))));
+    }
+    Ok(())
+}
+
+fn check_changed(statuses: &[MigrationStatus]) -> Result<()> {
+    match statuses.iter().find(|s| s.state == MigrationState::Changed) {
+        Some(changed) => Err(Error::Migration(format!(
+            "migration {} has changed since it was applied", changed.migration.m_id))),
+        None => Ok(())
+    }
+}
+
+// run f in a transaction, rolling back if it fails
+// (Connection::transaction needs a &mut Connection)
+pub(crate) fn in_transaction<T, F: FnOnce() -> Result<T>>(db: &Connection, f: F) -> Result<T> {
+    db.execute_batch("BEGIN IMMEDIATE;")?;
+    match f() {
+        Ok(t) => {
+            db.execute_batch("COMMIT;")?;
+            Ok(t)
+        }
+        Err(e) => {
+            if let Err(rollback) = db.execute_batch("ROLLBACK;") {
+                error!("Rollback failed: {}", rollback);
+            }
+            Err(e)
+        }
+    }
+}
 
 pub struct Migrator<'a> {
     db: &'a Connection
@@ -67,49 +277,276 @@ impl<'a> Migrator<'a> {
     }
 
     fn migrate(&self) -> Result<()> {
+        check_not_newer(self.db)?;
+        let statuses = self.status()?;
+        check_changed(&statuses)?;
         self.base_migration()?;
-        for migration in migrations() {
-            if !self.check_migration(&migration)? {
-                self.run_migration(&migration)?
+        for status in &statuses {
+            if status.state == MigrationState::Pending {
+                self.run_migration(&status.migration)?
             }
         }
-        Ok(())
+        self.fill_checksums(&statuses)
     }
 
-    // Use a different technique for the very first migration:
-    // check sqlite_master for the existence of the migrations table
-    fn base_migration(&self) -> Result<()> {
+    fn status(&self) -> Result<Vec<MigrationStatus>> {
+        let applied = self.applied()?;
+        Ok(migrations().into_iter().map(|migration| {
+            let recorded = applied.iter().find(|(m_id, _)| m_id == migration.m_id);
+            let state = match recorded {
+                None => MigrationState::Pending,
+                Some((_, None)) => MigrationState::Unverified,
+                Some((_, Some(checksum))) if *checksum == migration.checksum() => MigrationState::Applied,
+                Some(_) => MigrationState::Changed
+            };
+            MigrationStatus { migration, state }
+        }).collect())
+    }
+
+    // migration ids logged in the db, with checksums where recorded
+    fn applied(&self) -> Result<Vec<(String, Option<String>)>> {
+        if !self.has_migrations_table()? {
+            return Ok(vec![]);
+        }
+        let sql = if self.has_checksums() {
+            "select m_id, checksum from migrations"
+        } else {
+            "select m_id, null from migrations"
+        };
+        let mut stmt = self.db.prepare(sql)?;
+        let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?
+            .collect::<rusqlite::Result<Vec<(String, Option<String>)>>>()?;
+        Ok(rows)
+    }
+
+    fn has_migrations_table(&self) -> Result<bool> {
         let sql = "select name from sqlite_master where type='table' and name='migrations';";
         let res = self.db.query_row(sql, &[], |_| ());
         match res {
-            Ok(()) => Ok(()),
-            Err(Error::QueryReturnedNoRows) => self.run_migration(&base()),
-            e @ Err(_) => e
+            Ok(()) => Ok(true),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(Error::from(e))
         }
     }
 
-    // returt true if migration is already logged in db
-    fn check_migration(&self, migration: &Migration) -> Result<bool> {
-        let check_sql = "select 1 from migrations where m_id = ?";
-        let params : Vec<&dyn ToSql> = vec![&migration.m_id];
-        let res: Result<i64> = self.db.query_row(check_sql, &params,
-            |row| row.get(0));
-        match res {
-            Err(Error::SqliteFailure(_,_)) | // returned when no migration table
-            Err(Error::QueryReturnedNoRows) => Ok(false),
-            Err(e) => Err(e),
-            Ok(_) => Ok(true)
+    fn has_checksums(&self) -> bool {
+        self.db.prepare("select checksum from migrations").is_ok()
+    }
+
+    fn has_down_sql(&self) -> bool {
+        self.db.prepare("select down_sql from migrations").is_ok()
+    }
+
+    fn update_schema_version(&self) -> Result<()> {
+        let version: i64 = self.db.query_row(
+            "select count(*) from migrations where m_id<>?", &[&base().m_id], |row| row.get(0))?;
+        self.db.execute_batch(&format!("PRAGMA user_version = {};", version))?;
+        Ok(())
+    }
+
+    // Use a different technique for the very first migration:
+    // check sqlite_master for the existence of the migrations table
+    fn base_migration(&self) -> Result<()> {
+        if self.has_migrations_table()? {
+            Ok(())
+        } else {
+            self.run_migration(&base())
         }
     }
 
+    fn in_transaction<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        in_transaction(self.db, f)
+    }
+
     fn run_migration(&self, migration: &Migration) -> Result<()> {
         info!("run migration: {:?}", migration.m_id);
-        self.db.execute_batch(migration.m_sql)?;
-        self.db.execute(
-            "insert into migrations (m_id) values (?)",
-            &[ &migration.m_id ]
-        )?;
-        Ok(())
+        self.in_transaction(|| {
+            self.db.execute_batch(migration.m_sql)?;
+            // the migrations table gains columns as it's migrated
+            let checksum = migration.checksum();
+            let mut fields = vec!["m_id"];
+            let mut params: Vec<&dyn ToSql> = vec![&migration.m_id];
+            if self.has_checksums() {
+                fields.push("checksum");
+                params.push(&checksum);
+            }
+            if self.has_down_sql() {
+                fields.push("down_sql");
+                params.push(&migration.m_down);
+            }
+            let sql = format!("insert into migrations ({}) values ({})",
+                fields.join(","), vec!["?"; fields.len()].join(","));
+            self.db.execute(&sql, &params)?;
+            self.update_schema_version()
+        })
+    }
+
+    fn rollback(&self) -> Result<Option<String>> {
+        if !self.has_migrations_table()? {
+            return Ok(None);
+        }
+        let last: Option<String> = match self.db.query_row(
+            "select m_id from migrations where m_id<>? order by rowid desc limit 1",
+            &[&base().m_id], |row| row.get(0)) {
+            Ok(m_id) => Some(m_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(Error::from(e))
+        };
+        let m_id = match last {
+            Some(m_id) => m_id,
+            None => return Ok(None)
+        };
+        // prefer this binary's sql, fall back to what the migration stored
+        let down: Option<String> = match migrations().into_iter().find(|m| m.m_id == m_id) {
+            Some(migration) => migration.m_down.map(ToString::to_string),
+            None if self.has_down_sql() => self.db.query_row(
+                "select down_sql from migrations where m_id=?", &[&m_id], |row| row.get(0))?,
+            None => None
+        };
+        let down = down.ok_or_else(|| {
+            Error::Migration(format!("migration {} can't be rolled back", m_id))
+        })?;
+        info!("roll back migration: {:?}", m_id);
+        self.in_transaction(|| {
+            self.db.execute_batch(&down)?;
+            self.db.execute("delete from migrations where m_id=?", &[&m_id])?;
+            self.update_schema_version()
+        })?;
+        Ok(Some(m_id))
+    }
+
+    // record checksums of migrations applied before they were tracked
+    fn fill_checksums(&self, statuses: &[MigrationStatus]) -> Result<()> {
+        if !self.has_checksums() {
+            return Ok(());
+        }
+        self.in_transaction(|| {
+            for status in statuses {
+                if status.state != MigrationState::Changed {
+                    let checksum = status.migration.checksum();
+                    let params: Vec<&dyn ToSql> = vec![&checksum, &status.migration.m_id];
+                    self.db.execute(
+                        "update migrations set checksum=? where m_id=? and checksum is null",
+                        &params)?;
+                }
+            }
+            Ok(())
+        })
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(db: &Connection) -> Vec<MigrationState> {
+        status(db).expect("status").into_iter().map(|s| s.state).collect()
+    }
+
+    #[test]
+    fn checksum_ignores_whitespace() {
+        let a = Migration { m_id: "a", m_sql: "create table t (x);", m_down: None };
+        let b = Migration { m_id: "b", m_sql: "\n    create   table t\n (x);  ", m_down: None };
+        let c = Migration { m_id: "c", m_sql: "create table t (y);", m_down: None };
+        assert_eq!(a.checksum(), b.checksum());
+        assert_ne!(a.checksum(), c.checksum());
+    }
+
+    #[test]
+    fn migrate_and_verify() {
+        let db = Connection::open_in_memory().unwrap();
+        assert!(states(&db).iter().all(|s| *s == MigrationState::Pending));
+        assert!(verify(&db).is_err());
+        migrate(&db).expect("migrate");
+        assert!(states(&db).iter().all(|s| *s == MigrationState::Applied));
+        verify(&db).expect("verify");
+        assert_eq!(migrations().len() as i64, schema_version(&db).unwrap());
+    }
+
+    #[test]
+    fn refuse_newer_schema() {
+        let db = Connection::open_in_memory().unwrap();
+        migrate(&db).expect("migrate");
+        let future = Migration {
+            m_id: "from_the_future",
+            m_sql: "create table future (x);",
+            m_down: Some("drop table future;")
+        };
+        Migrator::new(&db).run_migration(&future).expect("future migration");
+        assert_eq!(vec!["from_the_future"], unknown(&db).unwrap());
+        match verify(&db) {
+            Err(Error::Migration(msg)) => assert!(msg.contains("from_the_future")),
+            other => panic!("expected newer schema error, got {:?}", other)
+        }
+        assert!(migrate(&db).is_err());
+        // the stored down sql lets this version roll it back
+        assert_eq!(Some("from_the_future".to_string()), rollback(&db).unwrap());
+        assert!(db.prepare("select x from future").is_err());
+        verify(&db).expect("verify");
+    }
+
+    #[test]
+    fn rollback_to_first() {
+        let db = Connection::open_in_memory().unwrap();
+        migrate(&db).expect("migrate");
+        assert_eq!(Some("action_phrases".to_string()), rollback(&db).unwrap());
+        assert_eq!(Some("idx_phrases_word2".to_string()), rollback(&db).unwrap());
+        assert_eq!(Some("blocklist".to_string()), rollback(&db).unwrap());
+        assert_eq!(Some("ignore_rules".to_string()), rollback(&db).unwrap());
+        assert_eq!(Some("stats".to_string()), rollback(&db).unwrap());
+        // the migrations table loses the columns these added
+        assert_eq!(Some("migrations_down_sql".to_string()), rollback(&db).unwrap());
+        assert!(!Migrator::new(&db).has_down_sql());
+        assert_eq!(Some("migrations_checksum".to_string()), rollback(&db).unwrap());
+        assert!(!Migrator::new(&db).has_checksums());
+        assert_eq!(Some("word_forms".to_string()), rollback(&db).unwrap());
+        assert!(db.prepare("select folded from word_forms").is_err());
+        assert_eq!(3, schema_version(&db).unwrap());
+        assert_eq!(Some("idx_phrases_backward".to_string()), rollback(&db).unwrap());
+        assert_eq!(Some("phrases_spelling_view".to_string()), rollback(&db).unwrap());
+        // rolling back the first migration would throw away everything learned
+        match rollback(&db) {
+            Err(Error::Migration(msg)) => assert!(msg.contains("words_and_phrases_init")),
+            other => panic!("expected irreversible migration, got {:?}", other)
+        }
+        // and migrating up again restores everything
+        migrate(&db).expect("migrate");
+        assert!(states(&db).iter().all(|s| *s == MigrationState::Applied));
+    }
+
+    #[test]
+    fn fill_unverified_checksums() {
+        let db = Connection::open_in_memory().unwrap();
+        migrate(&db).expect("migrate");
+        db.execute("update migrations set checksum=null where m_id='word_forms'", &[]).unwrap();
+        assert!(states(&db).contains(&MigrationState::Unverified));
+        verify(&db).expect("unverified is not an error");
+        migrate(&db).expect("migrate");
+        assert!(states(&db).iter().all(|s| *s == MigrationState::Applied));
+    }
+
+    #[test]
+    fn detect_changed_migration() {
+        let db = Connection::open_in_memory().unwrap();
+        migrate(&db).expect("migrate");
+        db.execute("update migrations set checksum='0' where m_id='word_forms'", &[]).unwrap();
+        assert!(states(&db).contains(&MigrationState::Changed));
+        match verify(&db) {
+            Err(Error::Migration(msg)) => assert!(msg.contains("word_forms")),
+            other => panic!("expected changed migration, got {:?}", other)
+        }
+        assert!(migrate(&db).is_err());
+    }
+
+    #[test]
+    fn failed_migration_rolls_back() {
+        let db = Connection::open_in_memory().unwrap();
+        migrate(&db).expect("migrate");
+        let bad = Migration { m_id: "bad", m_sql: "create table half (x); this is not sql;", m_down: None };
+        assert!(Migrator::new(&db).run_migration(&bad).is_err());
+        assert!(db.prepare("select x from half").is_err());
+        let logged: i64 = db.query_row(
+            "select count(*) from migrations where m_id='bad'", &[], |row| row.get(0)).unwrap();
+        assert_eq!(0, logged);
+    }
+}
@@ -0,0 +1,196 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use futures::future::join_all;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::ircconn::{BotConfig, IrcConn};
+use crate::markov_words::{self, OpenMode, WordsConfig, WordsDb};
+use crate::worker::WordsWorker;
+
+// the [[networks]] tables of a config file, the rest is the irc Config
+fn parse(data: &str, json: bool) -> std::result::Result<Vec<Config>, String> {
+    if json {
+        let file: serde_json::Value = serde_json::from_str(data).map_err(|e| e.to_string())?;
+        match file.get("networks") {
+            Some(networks) => serde_json::from_value(networks.clone()).map_err(|e| e.to_string()),
+            None => Ok(vec![])
+        }
+    } else {
+        let file: toml::Value = toml::from_str(data).map_err(|e| e.to_string())?;
+        match file.get("networks") {
+            Some(networks) => networks.clone().try_into().map_err(|e| e.to_string()),
+            None => Ok(vec![])
+        }
+    }
+}
+
+// options from the top of the file apply to every network without its own
+fn inherit(main: &Config, mut network: Config) -> Config {
+    for (option, value) in &main.options {
+        network.options.entry(option.clone()).or_insert_with(|| value.clone());
+    }
+    network
+}
+
+fn from_str(data: &str, json: bool, main: &Config) -> Result<Vec<Config>> {
+    let listed = parse(data, json).map_err(|e| Error::Config(format!("Couldn't read networks: {}", e)))?;
+    let mut networks = vec![];
+    // a server at the top of the file is a network too
+    if main.server.is_some() || listed.is_empty() {
+        networks.push(main.clone());
+    }
+    networks.extend(listed.into_iter().map(|network| inherit(main, network)));
+    Ok(networks)
+}
+
+// networks with the same words file share one WordsDb, opened with the
+// options of the first, so the others can't have their own
+fn check_shared(main: &Config, networks: &[Config]) -> Result<()> {
+    let mut brains: HashMap<String, (String, BTreeMap<&String, &String>)> = HashMap::new();
+    for config in std::iter::once(main).chain(networks) {
+        let db_url = WordsConfig::from_irc_config(config)?.db_url().to_string();
+        let options: BTreeMap<&String, &String> = config.options.iter()
+            .filter(|(option, _)| markov_words::is_words_option(option))
+            .collect();
+        let name = config.server.clone().unwrap_or_else(|| "the top level".to_string());
+        match brains.get(&db_url) {
+            Some((first, first_options)) if *first_options != options => return Err(Error::Config(format!(
+                "{} and {} share words file {} but set different learn, seed, backoff, fuzzy, \
+                 filter_* or block options", first, name, db_url))),
+            Some(_) => (),
+            None => {
+                brains.insert(db_url, (name, options));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The networks to connect to from the config file at path, already
+/// loaded as main: the top level server, and one per [[networks]]
+/// table (or "networks" array in json), each with its own server,
+/// nickname and channels
+pub fn load(path: &Path, main: &Config) -> Result<Vec<Config>> {
+    let data = fs::read_to_string(path)?;
+    let json = path.extension().and_then(|ext| ext.to_str()) == Some("json");
+    from_str(&data, json, main)
+}
+
+/// Connect to every network in the config file at path, each in its
+/// own task.  Networks with the same words file share main's database,
+/// or one opened for them; the rest open their own.  Returns once
+/// every network has quit, with the first error if any couldn't start.
+/// A network that can't be reached is retried until it can.
+pub async fn run(words: WordsDb, main: &Config, path: &Path) -> Result<()> {
+    let networks = load(path, main)?;
+    check_shared(main, &networks)?;
+    let mut workers: HashMap<String, WordsWorker> = HashMap::new();
+    workers.insert(WordsConfig::from_irc_config(main)?.db_url().to_string(), WordsWorker::spawn(words)?);
+    // back up each database from one network only
+    let mut backed_up = HashSet::new();
+    let mut bots = vec![];
+    for network in networks {
+        let db_url = WordsConfig::from_irc_config(&network)?.db_url().to_string();
+        if !workers.contains_key(&db_url) {
+            let mut words = WordsDb::from_config(&network, OpenMode::ReadWrite)?;
+            words.verify_migrations()?;
+            words.load_blocklist()?;
+            workers.insert(db_url.clone(), WordsWorker::spawn(words)?);
+        }
+        let mut bot = BotConfig::from_irc_config(&network)?;
+        if !backed_up.insert(db_url.clone()) {
+            bot.backups = None;
+        }
+        bots.push((workers[&db_url].clone(), network, bot));
+    }
+    let tasks = bots.into_iter().map(|(words, network, bot)| tokio::spawn(async move {
+        let server = network.server.clone().unwrap_or_default();
+        info!("Connecting to {}", server);
+        let mut irc = IrcConn::connect(words, network, bot).await.map_err(|e| {
+            error!("Couldn't start {}: {}", server, e);
+            e
+        })?;
+        irc.run().await;
+        Ok(())
+    }));
+    let mut result = Ok(());
+    for finished in join_all(tasks).await {
+        match finished {
+            Ok(Err(e)) if result.is_ok() => result = Err(e),
+            Err(e) => error!("Network task failed: {}", e),
+            _ => ()
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_NETWORKS: &str = r##"
+        nickname = "bazbot"
+        server = "irc.example.com"
+        channels = ["#bazbot"]
+
+        [options]
+        words = "shared.db"
+        interject = "0.01"
+
+        [[networks]]
+        nickname = "baz"
+        server = "irc.example.org"
+        channels = ["#chat", "#rust"]
+
+        [networks.options]
+        interject = "0.1"
+
+        [[networks]]
+        nickname = "quietbaz"
+        server = "irc.example.net"
+
+        [networks.options]
+        words = "separate.db"
+    "##;
+
+    #[test]
+    fn networks_inherit_options() {
+        let main: Config = toml::from_str(TWO_NETWORKS).expect("main config");
+        let networks = from_str(TWO_NETWORKS, false, &main).expect("networks");
+        let servers: Vec<_> = networks.iter().map(|n| n.server.clone().unwrap_or_default()).collect();
+        assert_eq!(vec!["irc.example.com", "irc.example.org", "irc.example.net"], servers);
+        assert_eq!(vec!["#chat", "#rust"], networks[1].channels);
+        assert_eq!(Some(&"0.1".to_string()), networks[1].options.get("interject"));
+        assert_eq!(Some(&"shared.db".to_string()), networks[1].options.get("words"));
+        assert_eq!(Some(&"separate.db".to_string()), networks[2].options.get("words"));
+        assert_eq!(Some(&"0.01".to_string()), networks[2].options.get("interject"));
+    }
+
+    #[test]
+    fn shared_words_options() {
+        let main: Config = toml::from_str(TWO_NETWORKS).expect("main config");
+        let networks = from_str(TWO_NETWORKS, false, &main).expect("networks");
+        check_shared(&main, &networks).expect("inherited options agree");
+        // the separate database may have its own
+        let mut separate = networks.clone();
+        separate[2].options.insert("learn".to_string(), "false".to_string());
+        check_shared(&main, &separate).expect("separate words file");
+        let mut shared = networks;
+        shared[1].options.insert("learn".to_string(), "false".to_string());
+        match check_shared(&main, &shared) {
+            Err(Error::Config(msg)) => assert!(msg.contains("irc.example.org"), "{}", msg),
+            other => panic!("expected a config error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn single_network() {
+        let json = r#"{"nickname": "bazbot", "server": "localhost", "options": {"words": "baz.db"}}"#;
+        let main: Config = serde_json::from_str(json).expect("main config");
+        assert_eq!(vec![main.clone()], from_str(json, true, &main).expect("networks"));
+        assert!(from_str("networks = 5", false, &main).is_err());
+    }
+}
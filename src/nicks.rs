@@ -0,0 +1,233 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::options::parse_option;
+
+const ZERO_WIDTH_SPACE: char = '\u{200B}';
+const DEFAULT_PLACEHOLDER: &str = "someone";
+
+// What to do to member nicks in the bot's own messages
+#[derive(Debug, PartialEq)]
+pub enum Disrupt {
+    Off,
+    // put a zero width space after the first letter, so clients don't highlight
+    ZeroWidth,
+    Placeholder(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NickConfig {
+    pub disrupt: Disrupt,
+    // start replies with "nick: " for whoever triggered them
+    pub address_sender: bool,
+}
+
+impl NickConfig {
+    pub fn from_irc_config(config: &Config) -> Result<NickConfig> {
+        let disrupt = match config.options.get("disrupt_nicks").map(AsRef::as_ref) {
+            Some("zero-width") | None => Disrupt::ZeroWidth,
+            Some("placeholder") => Disrupt::Placeholder(config.options.get("nick_placeholder")
+                .cloned().unwrap_or_else(|| DEFAULT_PLACEHOLDER.to_string())),
+            Some("off") => Disrupt::Off,
+            Some(other) => return Err(Error::Config(format!(
+                "disrupt_nicks should be zero-width, placeholder or off, not {}", other)))
+        };
+        let address_sender = parse_option(config, "address_sender", "bool", false)?;
+        Ok(NickConfig { disrupt, address_sender })
+    }
+}
+
+// letters nicks are made of, besides alphanumerics
+fn is_nick_char(c: char) -> bool {
+    c.is_alphanumeric() || "-_[]\\`^{}|".contains(c)
+}
+
+// Who's on each channel the bot is on, so replies don't highlight them
+pub struct Nicks {
+    config: NickConfig,
+    // lowercase channel to lowercase nicks
+    channels: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl Nicks {
+    pub fn new(config: NickConfig) -> Nicks {
+        Nicks { config, channels: Mutex::new(HashMap::new()) }
+    }
+
+    fn with_channels<T>(&self, f: impl FnOnce(&mut HashMap<String, HashSet<String>>) -> T) -> T {
+        let mut channels = match self.channels.lock() {
+            Ok(channels) => channels,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        f(&mut channels)
+    }
+
+    // one RPL_NAMREPLY line, nicks may have mode prefixes like @ or +
+    pub fn names(&self, channel: &str, names: &str) {
+        self.with_channels(|channels| {
+            let members = channels.entry(channel.to_lowercase()).or_insert_with(HashSet::new);
+            for name in names.split_whitespace() {
+                let nick = name.trim_start_matches(|c| "~&@%+".contains(c));
+                if !nick.is_empty() {
+                    members.insert(nick.to_lowercase());
+                }
+            }
+        })
+    }
+
+    pub fn join(&self, channel: &str, nick: &str) {
+        self.with_channels(|channels| {
+            channels.entry(channel.to_lowercase()).or_insert_with(HashSet::new).insert(nick.to_lowercase());
+        })
+    }
+
+    // the bot joined, NAMES replies follow
+    pub fn joined(&self, channel: &str) {
+        self.with_channels(|channels| channels.insert(channel.to_lowercase(), HashSet::new()));
+    }
+
+    // parted or kicked
+    pub fn part(&self, channel: &str, nick: &str) {
+        self.with_channels(|channels| {
+            if let Some(members) = channels.get_mut(&channel.to_lowercase()) {
+                members.remove(&nick.to_lowercase());
+            }
+        })
+    }
+
+    // the bot parted or was kicked
+    pub fn left(&self, channel: &str) {
+        self.with_channels(|channels| channels.remove(&channel.to_lowercase()));
+    }
+
+    // disconnected, channels are joined again from scratch
+    pub fn clear(&self) {
+        self.with_channels(HashMap::clear);
+    }
+
+    pub fn quit(&self, nick: &str) {
+        let nick = nick.to_lowercase();
+        self.with_channels(|channels| {
+            for members in channels.values_mut() {
+                members.remove(&nick);
+            }
+        })
+    }
+
+    pub fn rename(&self, old: &str, new: &str) {
+        let (old, new) = (old.to_lowercase(), new.to_lowercase());
+        self.with_channels(|channels| {
+            for members in channels.values_mut() {
+                if members.remove(&old) {
+                    members.insert(new.clone());
+                }
+            }
+        })
+    }
+
+    pub fn is_member(&self, channel: &str, nick: &str) -> bool {
+        self.with_channels(|channels| match channels.get(&channel.to_lowercase()) {
+            Some(members) => members.contains(&nick.to_lowercase()),
+            None => false
+        })
+    }
+
+    /// Disrupt the nicks of channel members in text, and of sender
+    /// wherever the reply goes, so nobody is highlighted by accident
+    pub fn disrupt(&self, channel: &str, sender: Option<&str>, text: &str) -> String {
+        if self.config.disrupt == Disrupt::Off {
+            return text.to_string();
+        }
+        let sender = sender.map(str::to_lowercase);
+        let words: Vec<String> = text.split(' ').map(|word| {
+            let nick = word.trim_matches(|c| !is_nick_char(c));
+            if nick.is_empty() {
+                return word.to_string();
+            }
+            let lower = nick.to_lowercase();
+            if sender.as_ref() != Some(&lower) && !self.is_member(channel, nick) {
+                return word.to_string();
+            }
+            let replacement = match &self.config.disrupt {
+                Disrupt::ZeroWidth => {
+                    let mut chars = nick.chars();
+                    let first = chars.next().map(String::from).unwrap_or_default();
+                    format!("{}{}{}", first, ZERO_WIDTH_SPACE, chars.as_str())
+                }
+                Disrupt::Placeholder(placeholder) => placeholder.clone(),
+                Disrupt::Off => nick.to_string()
+            };
+            word.replacen(nick, &replacement, 1)
+        }).collect();
+        words.join(" ")
+    }
+
+    // a reply to sender, addressed to them only if configured
+    pub fn reply(&self, channel: &str, sender: &str, text: &str) -> String {
+        let text = self.disrupt(channel, Some(sender), text);
+        if self.config.address_sender {
+            format!("{}: {}", sender, text)
+        } else {
+            text
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+
+    fn on_a(options: &[(&str, &str)]) -> Nicks {
+        let nicks = Nicks::new(NickConfig::from_irc_config(&irc_config(options)).unwrap());
+        nicks.joined("#a");
+        nicks.names("#a", "@Joel +ann bazbot");
+        nicks
+    }
+
+    #[test]
+    fn parse_config() {
+        let defaults = NickConfig::from_irc_config(&irc_config(&[])).unwrap();
+        assert_eq!(NickConfig { disrupt: Disrupt::ZeroWidth, address_sender: false }, defaults);
+        let config = NickConfig::from_irc_config(&irc_config(&[
+            ("disrupt_nicks", "placeholder"), ("nick_placeholder", "someone else"),
+            ("address_sender", "true")])).unwrap();
+        assert_eq!(Disrupt::Placeholder("someone else".to_string()), config.disrupt);
+        assert!(config.address_sender);
+        assert!(NickConfig::from_irc_config(&irc_config(&[("disrupt_nicks", "yes")])).is_err());
+    }
+
+    #[test]
+    fn track_members() {
+        let nicks = on_a(&[]);
+        assert!(nicks.is_member("#A", "joel"));
+        nicks.join("#a", "Sam");
+        nicks.rename("sam", "sammy");
+        assert!(nicks.is_member("#a", "sammy") && !nicks.is_member("#a", "sam"));
+        nicks.part("#a", "ann");
+        nicks.quit("joel");
+        assert!(!nicks.is_member("#a", "ann") && !nicks.is_member("#a", "joel"));
+        nicks.left("#a");
+        assert!(!nicks.is_member("#a", "sammy"));
+        nicks.join("#b", "sam");
+        nicks.clear();
+        assert!(!nicks.is_member("#b", "sam"));
+    }
+
+    #[test]
+    fn disrupt_members() {
+        let nicks = on_a(&[]);
+        assert_eq!("j\u{200B}oel: ask a\u{200B}nn, not me", nicks.disrupt("#a", None, "joel: ask ann, not me"));
+        assert_eq!("joel is elsewhere", nicks.disrupt("#b", None, "joel is elsewhere"));
+        // the sender is disrupted even when not a member
+        assert_eq!("hi s\u{200B}am", nicks.reply("#b", "Sam", "hi sam"));
+
+        let placeholder = on_a(&[("disrupt_nicks", "placeholder"), ("address_sender", "true")]);
+        assert_eq!("ann: someone said so", placeholder.reply("#a", "ann", "Joel said so"));
+        let off = on_a(&[("disrupt_nicks", "off")]);
+        assert_eq!("joel said so", off.reply("#a", "ann", "joel said so"));
+    }
+}
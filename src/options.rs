@@ -0,0 +1,43 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+/// An option from the config's options, or default when it isn't set.
+/// what says what was expected, as in "Couldn't parse learn as bool".
+pub fn parse_option<T: FromStr>(config: &Config, option: &str, what: &str, default: T) -> Result<T>
+where T::Err: fmt::Display {
+    match config.options.get(option) {
+        Some(value) => value.trim().parse().map_err(|e| {
+            Error::Config(format!("Couldn't parse {} as {} {}: {}", option, what, value, e))
+        }),
+        None => Ok(default)
+    }
+}
+
+// a config with only these options set, for tests
+#[cfg(test)]
+pub(crate) fn irc_config(options: &[(&str, &str)]) -> Config {
+    Config {
+        options: options.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        .. Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        let config = irc_config(&[("count", " 3 "), ("flag", "yes")]);
+        assert_eq!(3, parse_option(&config, "count", "a count", 1).unwrap());
+        assert_eq!(7, parse_option(&config, "missing", "a count", 7).unwrap());
+        match parse_option(&config, "flag", "bool", false) {
+            Err(Error::Config(msg)) => assert!(msg.starts_with("Couldn't parse flag as bool yes"), "{}", msg),
+            other => panic!("expected a config error, got {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,63 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+
+use crate::error::Result;
+use crate::options::parse_option;
+
+// How the bot treats private messages
+#[derive(Debug, PartialEq)]
+pub struct QueryConfig {
+    // learn from private messages
+    pub learn: bool,
+    // answer private messages
+    pub reply: bool,
+    // only answer private messages that mention the bot's nick
+    pub mention: bool,
+}
+
+impl QueryConfig {
+    pub fn from_irc_config(config: &Config) -> Result<QueryConfig> {
+        Ok(QueryConfig {
+            learn: parse_option(config, "query_learn", "bool", false)?,
+            reply: parse_option(config, "query_reply", "bool", true)?,
+            mention: parse_option(config, "query_mention", "bool", false)?
+        })
+    }
+}
+
+// channel names start with # or &, anything else is our own nick
+pub fn is_channel(target: &str) -> bool {
+    target.starts_with('#') || target.starts_with('&')
+}
+
+// where to answer a message to target: the channel, or the sender in a query
+pub fn reply_target<'a>(target: &'a str, sender: &'a str) -> &'a str {
+    if is_channel(target) {
+        target
+    } else {
+        sender
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+
+    #[test]
+    fn parse_config() {
+        let defaults = QueryConfig::from_irc_config(&irc_config(&[])).unwrap();
+        assert_eq!(QueryConfig { learn: false, reply: true, mention: false }, defaults);
+        let config = QueryConfig::from_irc_config(&irc_config(&[
+            ("query_learn", "true"), ("query_reply", "false"), ("query_mention", "true")])).unwrap();
+        assert_eq!(QueryConfig { learn: true, reply: false, mention: true }, config);
+        assert!(QueryConfig::from_irc_config(&irc_config(&[("query_learn", "yes")])).is_err());
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!("#rust", reply_target("#rust", "joel"));
+        assert_eq!("&local", reply_target("&local", "joel"));
+        assert_eq!("joel", reply_target("bazbot", "joel"));
+    }
+}
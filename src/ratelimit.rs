@@ -0,0 +1,232 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::options::parse_option;
+
+// longest a queued reply may wait before it's dropped instead
+const MAX_QUEUE_WAIT: Duration = Duration::from_secs(30);
+
+// A burst of count replies, refilled over per
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub count: u32,
+    pub per: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = Error;
+
+    // "5/60" is 5 replies per 60 seconds, "0/1" blocks everything
+    fn from_str(s: &str) -> Result<Rate> {
+        let mut parts = s.trim().splitn(2, '/');
+        let parsed = match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+            (Some(Ok(count)), Some(Ok(secs))) if secs > 0 => Some(Rate { count, per: Duration::from_secs(secs) }),
+            _ => None
+        };
+        parsed.ok_or_else(|| Error::Config(format!("rate should be count/seconds, like 5/60, not {}", s)))
+    }
+}
+
+// What to do with a reply over the limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    Drop,
+    // delay it until there's room, up to MAX_QUEUE_WAIT
+    Queue,
+}
+
+impl FromStr for Overflow {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Overflow> {
+        match s {
+            "drop" => Ok(Overflow::Drop),
+            "queue" => Ok(Overflow::Queue),
+            _ => Err(Error::Config(format!("rate_overflow should be drop or queue, not {}", s)))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RateConfig {
+    pub global: Rate,
+    pub channel: Rate,
+    pub user: Rate,
+    pub overflow: Overflow,
+}
+
+impl RateConfig {
+    pub fn from_irc_config(config: &Config) -> Result<RateConfig> {
+        let minute = Duration::from_secs(60);
+        Ok(RateConfig {
+            global: parse_option(config, "rate_global", "a rate", Rate { count: 20, per: minute })?,
+            channel: parse_option(config, "rate_channel", "a rate", Rate { count: 6, per: minute })?,
+            user: parse_option(config, "rate_user", "a rate", Rate { count: 3, per: minute })?,
+            overflow: match config.options.get("rate_overflow") {
+                Some(overflow) => overflow.parse()?,
+                None => Overflow::Drop
+            }
+        })
+    }
+}
+
+// Token bucket, tokens go negative while queued replies wait their turn
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Bucket {
+        Bucket { tokens: f64::from(rate.count), updated: now }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let per_sec = f64::from(rate.count) / rate.per.as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(f64::from(rate.count));
+        self.updated = now;
+    }
+
+    // how long until a token is free, None if never
+    fn wait(&self, rate: Rate) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            return Some(Duration::from_secs(0));
+        }
+        if rate.count == 0 {
+            return None;
+        }
+        let per_sec = f64::from(rate.count) / rate.per.as_secs_f64();
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Send,
+    // send after waiting
+    Wait(Duration),
+    // over the named limit
+    Drop(&'static str),
+}
+
+struct Buckets {
+    global: Bucket,
+    channels: HashMap<String, Bucket>,
+    users: HashMap<String, Bucket>,
+}
+
+// Limits replies globally, per channel and per user
+pub struct RateLimiter {
+    config: RateConfig,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateConfig) -> RateLimiter {
+        let global = Bucket::new(config.global, Instant::now());
+        RateLimiter {
+            config,
+            buckets: Mutex::new(Buckets { global, channels: HashMap::new(), users: HashMap::new() })
+        }
+    }
+
+    /// Take a token from every bucket a reply to user on channel counts
+    /// against, or say why it can't be sent.  user is None for replies
+    /// nobody asked for.
+    pub fn check(&self, channel: &str, user: Option<&str>, now: Instant) -> Decision {
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let Buckets { global, channels, users } = &mut *buckets;
+        let config = &self.config;
+        let channel = channels.entry(channel.to_lowercase())
+            .or_insert_with(|| Bucket::new(config.channel, now));
+        let mut limits: Vec<(&'static str, Rate, &mut Bucket)> = vec![
+            ("global", config.global, global), ("channel", config.channel, channel)];
+        if let Some(user) = user {
+            let user = users.entry(user.to_lowercase())
+                .or_insert_with(|| Bucket::new(config.user, now));
+            limits.push(("user", config.user, user));
+        }
+        let mut wait = Duration::from_secs(0);
+        for (name, rate, bucket) in limits.iter_mut() {
+            bucket.refill(*rate, now);
+            match bucket.wait(*rate) {
+                Some(w) if w == Duration::from_secs(0) => (),
+                Some(w) if config.overflow == Overflow::Queue && w <= MAX_QUEUE_WAIT => wait = wait.max(w),
+                _ => return Decision::Drop(name)
+            }
+        }
+        for (_, _, bucket) in limits.iter_mut() {
+            bucket.tokens -= 1.0;
+        }
+        if wait == Duration::from_secs(0) {
+            Decision::Send
+        } else {
+            Decision::Wait(wait)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+
+    fn limiter(overflow: Overflow) -> RateLimiter {
+        let minute = Duration::from_secs(60);
+        RateLimiter::new(RateConfig {
+            global: Rate { count: 4, per: minute },
+            channel: Rate { count: 3, per: minute },
+            user: Rate { count: 2, per: Duration::from_secs(50) },
+            overflow
+        })
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(Rate { count: 5, per: Duration::from_secs(60) }, "5/60".parse().unwrap());
+        assert!("5".parse::<Rate>().is_err());
+        assert!("5/0".parse::<Rate>().is_err());
+        let config = RateConfig::from_irc_config(&irc_config(&[("rate_user", "1/10"), ("rate_overflow", "queue")])).unwrap();
+        assert_eq!(Rate { count: 1, per: Duration::from_secs(10) }, config.user);
+        assert_eq!(Overflow::Queue, config.overflow);
+    }
+
+    #[test]
+    fn drop_over_limits() {
+        let limits = limiter(Overflow::Drop);
+        let now = Instant::now();
+        assert_eq!(Decision::Send, limits.check("#a", Some("joe"), now));
+        assert_eq!(Decision::Send, limits.check("#a", Some("JOE"), now));
+        assert_eq!(Decision::Drop("user"), limits.check("#a", Some("joe"), now));
+        assert_eq!(Decision::Send, limits.check("#a", Some("ann"), now));
+        assert_eq!(Decision::Drop("channel"), limits.check("#a", None, now));
+        assert_eq!(Decision::Send, limits.check("#b", None, now));
+        assert_eq!(Decision::Drop("global"), limits.check("#c", None, now));
+        // a user token comes back every 25 seconds
+        let later = now + Duration::from_secs(31);
+        assert_eq!(Decision::Send, limits.check("#b", Some("joe"), later));
+    }
+
+    #[test]
+    fn queue_over_limits() {
+        let limits = limiter(Overflow::Queue);
+        let now = Instant::now();
+        limits.check("#a", Some("joe"), now);
+        limits.check("#a", Some("joe"), now);
+        match limits.check("#a", Some("joe"), now) {
+            Decision::Wait(wait) => assert!(wait > Duration::from_secs(24) && wait <= MAX_QUEUE_WAIT, "{:?}", wait),
+            other => panic!("expected to wait, got {:?}", other)
+        }
+        // the user has a reply queued already, a second would wait 50s
+        assert_eq!(Decision::Drop("user"), limits.check("#a", Some("joe"), now));
+    }
+}
@@ -0,0 +1,65 @@
+extern crate irc;
+use self::irc::client::data::config::Config;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::options::parse_option;
+
+const DEFAULT_MIN_SECS: u64 = 5;
+const DEFAULT_MAX_SECS: u64 = 600;
+
+// How long to wait before connecting again after losing the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectConfig {
+    // first wait, doubled after every failed attempt
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl ReconnectConfig {
+    pub fn from_irc_config(config: &Config) -> Result<ReconnectConfig> {
+        let min = Duration::from_secs(parse_option(config, "reconnect_min", "seconds", DEFAULT_MIN_SECS)?);
+        let max = Duration::from_secs(parse_option(config, "reconnect_max", "seconds", DEFAULT_MAX_SECS)?);
+        if min.as_secs() == 0 || max < min {
+            return Err(Error::Config(format!(
+                "reconnect_min should be at least 1 and at most reconnect_max, not {:?} and {:?}", min, max)));
+        }
+        Ok(ReconnectConfig { min, max })
+    }
+
+    /// Wait before the attempt'th reconnection in a row, starting from
+    /// 0.  roll is uniform in [0, 1), from rand outside of tests, and
+    /// spreads the wait from half to one and a half times the backoff
+    /// so bots on a restarted server don't all come back at once.
+    pub fn delay(&self, attempt: u32, roll: f64) -> Duration {
+        let backoff = self.min.checked_mul(2u32.saturating_pow(attempt.min(31)))
+            .unwrap_or(self.max)
+            .min(self.max);
+        backoff.mul_f64(0.5 + roll).min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::irc_config;
+
+    #[test]
+    fn parse_config() {
+        let config = ReconnectConfig::from_irc_config(&irc_config(&[("reconnect_max", "60")])).unwrap();
+        assert_eq!(ReconnectConfig { min: Duration::from_secs(5), max: Duration::from_secs(60) }, config);
+        for bad in &[("reconnect_min", "0"), ("reconnect_min", "soon"), ("reconnect_max", "1")] {
+            assert!(ReconnectConfig::from_irc_config(&irc_config(&[*bad])).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let config = ReconnectConfig { min: Duration::from_secs(5), max: Duration::from_secs(60) };
+        assert_eq!(Duration::from_secs(5), config.delay(0, 0.5));
+        assert_eq!(Duration::from_secs(20), config.delay(2, 0.5));
+        assert_eq!(Duration::from_secs(10), config.delay(2, 0.0));
+        assert_eq!(Duration::from_secs(60), config.delay(4, 0.9));
+        assert_eq!(Duration::from_secs(30), config.delay(100, 0.0));
+    }
+}
@@ -0,0 +1,166 @@
+use rusqlite::Connection;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+// What the bot counts, per day and channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Counter {
+    Lines,
+    NewWords,
+    NewTrigrams,
+    Replies,
+    Errors,
+    // replies dropped by rate limits
+    Limited,
+    // counted under the server rather than a channel
+    Reconnects,
+}
+
+pub const COUNTERS: [Counter; 7] = [
+    Counter::Lines, Counter::NewWords, Counter::NewTrigrams, Counter::Replies, Counter::Errors,
+    Counter::Limited, Counter::Reconnects];
+
+impl Counter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Lines => "lines",
+            Counter::NewWords => "words",
+            Counter::NewTrigrams => "trigrams",
+            Counter::Replies => "replies",
+            Counter::Errors => "errors",
+            Counter::Limited => "limited",
+            Counter::Reconnects => "reconnects",
+        }
+    }
+}
+
+impl fmt::Display for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Counter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Counter> {
+        COUNTERS.iter().cloned()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| Error::Config(format!(
+                "Unknown counter {}, expected one of: {}", s,
+                COUNTERS.iter().map(|c| c.name()).collect::<Vec<_>>().join(", "))))
+    }
+}
+
+// Counters for one day (UTC, YYYY-MM-DD) and channel
+#[derive(Debug, PartialEq)]
+pub struct DayStats {
+    pub day: String,
+    pub channel: String,
+    pub counts: [i64; 7],
+}
+
+impl DayStats {
+    pub fn get(&self, counter: Counter) -> i64 {
+        self.counts[counter as usize]
+    }
+}
+
+// add n to today's counter for channel
+pub fn record(db: &Connection, channel: &str, counter: Counter, n: i64) -> Result<()> {
+    if n == 0 {
+        return Ok(());
+    }
+    record_day(db, "+0 days", channel, counter, n)
+}
+
+// shift is a date() modifier from today, like "-1 day"
+fn record_day(db: &Connection, shift: &str, channel: &str, counter: Counter, n: i64) -> Result<()> {
+    let name = counter.name();
+    db.execute(
+        "insert or ignore into stats (day, channel, counter, count) values (date('now', ?), ?, ?, 0)",
+        &[&shift, &channel, &name])?;
+    db.execute(
+        "update stats set count=count+? where day=date('now', ?) and channel=? and counter=?",
+        &[&n, &shift, &channel, &name])?;
+    Ok(())
+}
+
+/// Counters for the last days days, oldest first, one row per day and
+/// channel that had any activity.  channel limits it to one channel.
+pub fn history(db: &Connection, days: u32, channel: Option<&str>) -> Result<Vec<DayStats>> {
+    let since = format!("-{} days", days.saturating_sub(1));
+    let mut stmt = db.prepare(
+        "select day, channel, counter, count from stats
+         where day >= date('now', ?) and (? is null or channel = ?)
+         order by day, channel")?;
+    let mut rows = stmt.query(&[&since, &channel, &channel])?;
+    let mut history: Vec<DayStats> = vec![];
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let day: String = row.get(0);
+        let channel: String = row.get(1);
+        let name: String = row.get(2);
+        let count: i64 = row.get(3);
+        let counter = match name.parse::<Counter>() {
+            Ok(counter) => counter,
+            // written by a newer bazbot
+            Err(_) => continue
+        };
+        let same = match history.last() {
+            Some(last) => last.day == day && last.channel == channel,
+            None => false
+        };
+        if !same {
+            history.push(DayStats { day, channel, counts: [0; 7] });
+        }
+        if let Some(last) = history.last_mut() {
+            last.counts[counter as usize] += count;
+        }
+    }
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migration;
+
+    fn migrated() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        migration::migrate(&db).expect("migrate");
+        db
+    }
+
+    #[test]
+    fn counter_names() {
+        for counter in &COUNTERS {
+            assert_eq!(*counter, counter.name().parse().unwrap());
+        }
+        assert!("bananas".parse::<Counter>().is_err());
+    }
+
+    #[test]
+    fn record_and_history() {
+        let db = migrated();
+        record(&db, "#a", Counter::Lines, 2).unwrap();
+        record(&db, "#a", Counter::Lines, 3).unwrap();
+        record(&db, "#a", Counter::Replies, 1).unwrap();
+        record(&db, "#b", Counter::Errors, 1).unwrap();
+        record_day(&db, "-1 day", "#a", Counter::NewWords, 4).unwrap();
+        record_day(&db, "-30 days", "#a", Counter::Lines, 9).unwrap();
+
+        let history = history(&db, 7, None).unwrap();
+        assert_eq!(3, history.len());
+        assert_eq!(("#a", 4), (history[0].channel.as_str(), history[0].get(Counter::NewWords)));
+        assert_eq!(5, history[1].get(Counter::Lines));
+        assert_eq!(1, history[1].get(Counter::Replies));
+        assert_eq!(("#b", 1), (history[2].channel.as_str(), history[2].get(Counter::Errors)));
+
+        let only_b = super::history(&db, 7, Some("#b")).unwrap();
+        assert_eq!(1, only_b.len());
+        assert_eq!(3, super::history(&db, 31, Some("#a")).unwrap().len());
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::mpsc;
+use std::thread;
+use tokio::sync::oneshot;
+
+use crate::error::{Error, Result};
+use crate::markov_words::WordsDb;
+
+type Job = Box<dyn FnOnce(&mut WordsDb) + Send>;
+
+// Owns the WordsDb on a dedicated thread so sqlite calls never run on
+// the async reactor.  Handles are cheap to clone and queue jobs that run
+// one at a time, in the order they were sent.
+#[derive(Clone)]
+pub struct WordsWorker {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl WordsWorker {
+    pub fn spawn(mut words: WordsDb) -> Result<WordsWorker> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("words".to_string())
+            .spawn(move || {
+                // ends when every handle has been dropped
+                for job in queue {
+                    job(&mut words);
+                }
+                debug!("words worker stopped");
+            })?;
+        Ok(WordsWorker { jobs })
+    }
+
+    // Run f against the database on the worker thread and wait for the result
+    pub async fn call<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut WordsDb) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |words| {
+            // the caller may have given up waiting, that's fine
+            let _ = tx.send(f(words));
+        });
+        self.jobs.send(job).map_err(|_| Error::WorkerGone)?;
+        rx.await.map_err(|_| Error::WorkerGone)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn call_runs_on_worker() {
+        let words = WordsDb::new(":memory:".to_string()).expect("open");
+        let worker = WordsWorker::spawn(words).expect("spawn");
+        worker.call(|words| words.migrate()).await.expect("migrate");
+        worker.call(|words| words.add_line("a b c")).await.expect("add");
+        let complete = worker.call(|words| words.complete(&["a", "b"])).await.expect("complete");
+        assert_eq!(vec!["c", ""], complete);
+        let thread = worker.call(|_| Ok(thread::current().name().map(ToString::to_string))).await;
+        assert_eq!(Some("words".to_string()), thread.expect("name"));
+    }
+}